@@ -2,7 +2,7 @@ use criterion::{criterion_group, criterion_main, Criterion};
 use criterion_linux_perf::{PerfMeasurement, PerfMode};
 
 fn timeit(crit: &mut Criterion<PerfMeasurement>) {
-    crit.bench_function("String::new", |b| b.iter(|| String::new()));
+    crit.bench_function("String::new", |b| b.iter(String::new));
     crit.bench_function("String::from", |b| b.iter(|| String::from("")));
 }
 
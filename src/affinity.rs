@@ -0,0 +1,53 @@
+use std::{io, mem};
+
+use crate::PerfError;
+
+/// Pins the thread that created it to a single CPU, restoring the
+/// thread's previous CPU affinity when dropped.
+pub(crate) struct Affinity {
+    tid: libc::pid_t,
+    previous: libc::cpu_set_t,
+}
+
+impl Affinity {
+    pub(crate) fn pin(cpu: usize) -> Result<Self, PerfError> {
+        let error = |error: io::Error| {
+            PerfError::Io(io::Error::new(
+                error.kind(),
+                format!("cannot pin the thread to CPU {cpu}: {error}"),
+            ))
+        };
+        if cpu >= libc::CPU_SETSIZE as usize {
+            return Err(error(io::ErrorKind::InvalidInput.into()));
+        }
+        // SAFETY: `cpu_set_t` is a plain bit mask, for which all zeroes
+        // is the empty set, and both calls are given its exact size.
+        unsafe {
+            let tid = libc::gettid();
+            let mut previous: libc::cpu_set_t = mem::zeroed();
+            if libc::sched_getaffinity(0, mem::size_of_val(&previous), &raw mut previous) != 0 {
+                return Err(error(io::Error::last_os_error()));
+            }
+            let mut set: libc::cpu_set_t = mem::zeroed();
+            libc::CPU_SET(cpu, &mut set);
+            if libc::sched_setaffinity(0, mem::size_of_val(&set), &raw const set) != 0 {
+                return Err(error(io::Error::last_os_error()));
+            }
+            Ok(Self { tid, previous })
+        }
+    }
+}
+
+impl Drop for Affinity {
+    fn drop(&mut self) {
+        // SAFETY: as above. The thread may have exited by now, in which
+        // case there is nothing left to restore and the error is moot.
+        unsafe {
+            libc::sched_setaffinity(
+                self.tid,
+                mem::size_of_val(&self.previous),
+                &raw const self.previous,
+            );
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use crate::{CounterOptions, Fallback, Migrations, PerfMeasurement, PerfMode};
+
+/// The settings of a [`PerfMeasurement`], held as plain data so that a
+/// measurement can be described in a configuration file.
+///
+/// With the `serde` feature enabled, this can be deserialized from any
+/// format supported by `serde`. Every field is optional and defaults to
+/// the default of the corresponding [`PerfMeasurement`] setting. The
+/// event is written as its `perf list` name. For example, in TOML:
+///
+/// ```toml
+/// event = "cpu-cycles"
+/// label = "core cycles"
+/// exclude_kernel = false
+/// pin_to_cpu = 2
+/// migrations = "fail"
+/// fallback = "task-clock"
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct PerfConfig {
+    /// The event to measure.
+    pub event: PerfMode,
+    /// The unit that values are reported in, if not the one named after
+    /// the event, as for [`PerfMeasurement::with_label`].
+    pub label: Option<String>,
+    /// Whether to exclude events in user space, as for
+    /// [`PerfMeasurement::exclude_user`].
+    pub exclude_user: bool,
+    /// Whether to exclude events in the kernel, as for
+    /// [`PerfMeasurement::exclude_kernel`].
+    pub exclude_kernel: bool,
+    /// Whether to exclude events in the hypervisor, as for
+    /// [`PerfMeasurement::exclude_hv`].
+    pub exclude_hv: bool,
+    /// Whether to exclude events while the CPU is idle, as for
+    /// [`PerfMeasurement::exclude_idle`].
+    pub exclude_idle: bool,
+    /// Whether threads created while the counter is open are counted,
+    /// as for [`PerfMeasurement::inherit`].
+    pub inherit: bool,
+    /// The CPU to pin the measuring thread to, if any, as for
+    /// [`PerfMeasurement::pin_to_cpu`].
+    pub pin_to_cpu: Option<usize>,
+    /// How samples with CPU migrations are handled, as for
+    /// [`PerfMeasurement::migrations`].
+    pub migrations: Migrations,
+    /// What to measure instead if the event cannot be opened, as for
+    /// [`PerfMeasurement::with_fallback`]. Without a fallback, failing
+    /// to open the event makes the benchmark fail.
+    pub fallback: Option<Fallback>,
+}
+
+impl Default for PerfConfig {
+    fn default() -> Self {
+        let options = CounterOptions::default();
+        Self {
+            event: PerfMode::Instructions,
+            label: None,
+            exclude_user: options.exclude_user,
+            exclude_kernel: options.exclude_kernel,
+            exclude_hv: options.exclude_hv,
+            exclude_idle: options.exclude_idle,
+            inherit: options.inherit,
+            pin_to_cpu: None,
+            migrations: Migrations::default(),
+            fallback: None,
+        }
+    }
+}
+
+impl PerfMeasurement {
+    /// Create a new measurement with the given settings.
+    #[must_use]
+    pub fn from_config(config: &PerfConfig) -> Self {
+        let mut measurement = Self::new(config.event)
+            .exclude_user(config.exclude_user)
+            .exclude_kernel(config.exclude_kernel)
+            .exclude_hv(config.exclude_hv)
+            .exclude_idle(config.exclude_idle)
+            .inherit(config.inherit)
+            .migrations(config.migrations);
+        if let Some(label) = &config.label {
+            measurement = measurement.with_label(label.clone());
+        }
+        if let Some(cpu) = config.pin_to_cpu {
+            measurement = measurement.pin_to_cpu(cpu);
+        }
+        match config.fallback {
+            Some(fallback) => measurement.or_fallback(fallback),
+            None => measurement,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for PerfMode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for PerfMode {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deserialize_documented_config() {
+        let config: PerfConfig = toml::from_str(
+            r#"
+            event = "cpu-cycles"
+            label = "core cycles"
+            exclude_kernel = false
+            pin_to_cpu = 2
+            migrations = "fail"
+            fallback = "task-clock"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            PerfConfig {
+                event: PerfMode::Cycles,
+                label: Some("core cycles".to_owned()),
+                exclude_kernel: false,
+                pin_to_cpu: Some(2),
+                migrations: Migrations::Fail,
+                fallback: Some(Fallback::TaskClock),
+                ..PerfConfig::default()
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_perf_names() {
+        let config: PerfConfig = toml::from_str(r#"event = "LLC-load-misses""#).unwrap();
+        assert_eq!(config.event.to_string(), "LLC-load-misses");
+        let config: PerfConfig = toml::from_str(r#"fallback = "wall-time""#).unwrap();
+        assert_eq!(config.fallback, Some(Fallback::WallTime));
+        assert_eq!(
+            toml::from_str::<PerfConfig>("").unwrap(),
+            PerfConfig::default()
+        );
+        assert!(toml::from_str::<PerfConfig>(r#"event = "cycle""#).is_err());
+        assert!(toml::from_str::<PerfConfig>(r#"migrations = "Fail""#).is_err());
+    }
+}
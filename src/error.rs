@@ -0,0 +1,132 @@
+use std::{fmt, fs, io};
+
+const PARANOID_PATH: &str = "/proc/sys/kernel/perf_event_paranoid";
+
+/// An error encountered while opening or operating a perf counter.
+#[derive(Debug)]
+pub enum PerfError {
+    /// The kernel refused to open the counter for lack of privilege.
+    /// This usually means `kernel.perf_event_paranoid` is set too high
+    /// or the process lacks `CAP_PERFMON`.
+    PermissionDenied {
+        /// The value of `/proc/sys/kernel/perf_event_paranoid`, if it
+        /// could be read.
+        paranoid: Option<i32>,
+        /// The underlying error.
+        source: io::Error,
+    },
+    /// The kernel refused to open a system-wide counter for lack of
+    /// privilege. Counting every process on a CPU requires
+    /// `kernel.perf_event_paranoid` to be 0 or lower, or the
+    /// `CAP_PERFMON` capability.
+    SystemWidePermissionDenied {
+        /// The value of `/proc/sys/kernel/perf_event_paranoid`, if it
+        /// could be read.
+        paranoid: Option<i32>,
+        /// The underlying error.
+        source: io::Error,
+    },
+    /// The requested event is not supported by this CPU or kernel
+    /// (`ENOENT`, `ENODEV` or `EOPNOTSUPP`).
+    Unsupported(io::Error),
+    /// No more counters could be opened, either because the hardware
+    /// has run out of counter registers or the process has run out of
+    /// file descriptors (`ENOSPC` or `EMFILE`).
+    TooManyCounters(io::Error),
+    /// An event specification could not be parsed or resolved.
+    InvalidEvent(io::Error),
+    /// The kernel refused to open inherited counters as part of a
+    /// counter group, which some kernel versions do not support.
+    InheritGroupUnsupported(io::Error),
+    /// Any other I/O error.
+    Io(io::Error),
+}
+
+impl PerfError {
+    /// The underlying I/O error.
+    #[must_use]
+    pub fn io_error(&self) -> &io::Error {
+        match self {
+            Self::PermissionDenied { source, .. }
+            | Self::SystemWidePermissionDenied { source, .. }
+            | Self::Unsupported(source)
+            | Self::TooManyCounters(source)
+            | Self::InvalidEvent(source)
+            | Self::InheritGroupUnsupported(source)
+            | Self::Io(source) => source,
+        }
+    }
+}
+
+impl From<io::Error> for PerfError {
+    fn from(error: io::Error) -> Self {
+        match error.raw_os_error() {
+            Some(libc::EACCES | libc::EPERM) => Self::PermissionDenied {
+                paranoid: read_paranoid(),
+                source: error,
+            },
+            Some(libc::ENOENT | libc::ENODEV | libc::EOPNOTSUPP) => Self::Unsupported(error),
+            Some(libc::ENOSPC | libc::EMFILE) => Self::TooManyCounters(error),
+            _ => Self::Io(error),
+        }
+    }
+}
+
+impl fmt::Display for PerfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::PermissionDenied { paranoid, source } => {
+                write!(f, "permission denied opening perf counter ({source}); ")?;
+                if let Some(level) = paranoid {
+                    write!(f, "{PARANOID_PATH} is {level}, ")?;
+                }
+                write!(
+                    f,
+                    "try lowering kernel.perf_event_paranoid (2 permits counting user space, \
+                     1 also the kernel) or grant the CAP_PERFMON capability"
+                )
+            }
+            Self::SystemWidePermissionDenied { paranoid, source } => {
+                write!(
+                    f,
+                    "permission denied opening system-wide perf counter ({source}); "
+                )?;
+                if let Some(level) = paranoid {
+                    write!(f, "{PARANOID_PATH} is {level}, ")?;
+                }
+                write!(
+                    f,
+                    "counting every process on a CPU requires kernel.perf_event_paranoid \
+                     to be 0 or lower, or the CAP_PERFMON capability"
+                )
+            }
+            Self::Unsupported(source) => write!(
+                f,
+                "perf event is not supported by this CPU or kernel ({source}); \
+                 virtual machines and containers often lack hardware counters"
+            ),
+            Self::TooManyCounters(source) => write!(
+                f,
+                "too many perf counters are open ({source}); \
+                 reduce the number of events or raise the open file limit"
+            ),
+            Self::InvalidEvent(source) => write!(f, "invalid perf event: {source}"),
+            Self::InheritGroupUnsupported(source) => write!(
+                f,
+                "this kernel cannot inherit counters in a group ({source}); \
+                 measure the events separately or disable inherit"
+            ),
+            Self::Io(source) => write!(f, "could not open perf counter: {source}"),
+        }
+    }
+}
+
+impl std::error::Error for PerfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.io_error())
+    }
+}
+
+fn read_paranoid() -> Option<i32> {
+    fs::read_to_string(PARANOID_PATH).ok()?.trim().parse().ok()
+}
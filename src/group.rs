@@ -0,0 +1,468 @@
+use std::{
+    cell::Cell,
+    fs::File,
+    io, mem,
+    os::{
+        raw::{c_int, c_uint, c_ulong},
+        unix::io::{AsRawFd, FromRawFd},
+    },
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use criterion::measurement::{Measurement, ValueFormatter};
+use perf_event::events::Software;
+use perf_event_open_sys::{bindings, ioctls};
+
+use crate::{CounterOptions, Multiplexing, PerThread, PerfError, PerfFormatter, PerfMode, Source};
+
+/// A measurement that counts several events at once, using a perf
+/// counter group so that all of the counters cover exactly the same
+/// stretch of execution.
+///
+/// Only the primary (first) event is reported to Criterion. The values
+/// of every event in the group are recorded for each sample, and can be
+/// retrieved through the [`GroupSamples`] handle returned by
+/// [`samples`](Self::samples), or written to a JSON file next to
+/// Criterion's own output with [`GroupSamples::write_report`].
+///
+/// ```
+/// use criterion::Criterion;
+/// use criterion_linux_perf::{PerfGroupMeasurement, PerfMode};
+///
+/// let measurement = PerfGroupMeasurement::new(PerfMode::Instructions)
+///     .member(PerfMode::Cycles)
+///     .member(PerfMode::BranchMisses);
+/// let samples = measurement.samples();
+/// let criterion = Criterion::default().with_measurement(measurement);
+/// ```
+#[derive(Clone)]
+pub struct PerfGroupMeasurement {
+    members: Vec<Source>,
+    formatter: PerfFormatter,
+    group: PerThread<GroupCounters>,
+    options: CounterOptions,
+    samples: GroupSamples,
+    multiplexing: Multiplexing,
+    warned: Cell<bool>,
+}
+
+/// An open counter group, with one counter for each member.
+///
+/// The group is opened directly rather than with `perf_event::Group`,
+/// whose placeholder leader is never inherited. The kernel refuses to
+/// add inherited members to a leader that is not inherited itself.
+pub(crate) struct GroupCounters {
+    leader: File,
+    members: Vec<File>,
+    ids: Vec<u64>,
+}
+
+/// The values of the counters of a group, in the order the members
+/// were given to [`GroupCounters::open`], and the times the group was
+/// enabled and running.
+pub(crate) struct GroupCounts {
+    pub(crate) values: Vec<u64>,
+    pub(crate) enabled: u64,
+    pub(crate) running: u64,
+}
+
+/// The read format of the group leader, which reads every member at
+/// once along with its ID.
+const GROUP_READ_FORMAT: u64 = (bindings::PERF_FORMAT_TOTAL_TIME_ENABLED
+    | bindings::PERF_FORMAT_TOTAL_TIME_RUNNING
+    | bindings::PERF_FORMAT_ID
+    | bindings::PERF_FORMAT_GROUP) as u64;
+
+impl GroupCounters {
+    pub(crate) fn open(members: &[Source], options: &CounterOptions) -> Result<Self, PerfError> {
+        let leader = Source::Event(Software::DUMMY.into()).builder(options);
+        let mut attrs = *leader.attrs();
+        attrs.read_format = GROUP_READ_FORMAT;
+        let leader = open_event(&mut attrs, -1).map_err(|error| match error.raw_os_error() {
+            Some(libc::EINVAL) if options.inherit => PerfError::InheritGroupUnsupported(error),
+            _ => error.into(),
+        })?;
+        let members = members
+            .iter()
+            .map(|source| {
+                // Members count whenever the leader is enabled.
+                let mut attrs = *source.builder(options).attrs();
+                attrs.set_disabled(0);
+                open_event(&mut attrs, leader.as_raw_fd())
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let ids = members
+            .iter()
+            .map(|member| {
+                let mut id = 0;
+                // SAFETY: the `ID` ioctl writes a single `u64`.
+                check(unsafe { ioctls::ID(member.as_raw_fd(), &raw mut id) })?;
+                Ok(id)
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            leader,
+            members,
+            ids,
+        })
+    }
+
+    pub(crate) fn probe(&mut self) -> io::Result<()> {
+        self.ioctl(ioctls::ENABLE)?;
+        self.ioctl(ioctls::DISABLE)
+    }
+
+    /// Start counting, returning the value of each counter at the
+    /// start of the sample.
+    ///
+    /// Resetting the group does not clear the counts that inherited
+    /// counters collected from threads that have since exited, so these
+    /// are read after the reset and subtracted from the values at the
+    /// end of the sample.
+    pub(crate) fn start(&mut self) -> io::Result<GroupCounts> {
+        self.ioctl(ioctls::RESET)?;
+        let starts = self.read()?;
+        self.ioctl(ioctls::ENABLE)?;
+        Ok(starts)
+    }
+
+    /// Stop counting and return the counts over the sample.
+    pub(crate) fn stop(&mut self, starts: &GroupCounts) -> io::Result<GroupCounts> {
+        self.ioctl(ioctls::DISABLE)?;
+        let ends = self.read()?;
+        Ok(GroupCounts {
+            values: ends
+                .values
+                .iter()
+                .zip(&starts.values)
+                .map(|(end, start)| end - start)
+                .collect(),
+            enabled: ends.enabled - starts.enabled,
+            running: ends.running - starts.running,
+        })
+    }
+
+    /// Read the counts of the group.
+    fn read(&self) -> io::Result<GroupCounts> {
+        // The group is read as the number of counters, the enabled and
+        // running times, and a value and ID for each counter, including
+        // the leader.
+        let mut data = vec![0_u64; 3 + 2 * (self.members.len() + 1)];
+        let len = mem::size_of_val(data.as_slice());
+        // SAFETY: the buffer holds `len` bytes.
+        let read = unsafe { libc::read(self.leader.as_raw_fd(), data.as_mut_ptr().cast(), len) };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let values = &data[3..];
+        let values = self
+            .ids
+            .iter()
+            .map(|&id| {
+                values
+                    .chunks_exact(2)
+                    .find(|pair| pair[1] == id)
+                    .map(|pair| pair[0])
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "group member missing from read")
+                    })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(GroupCounts {
+            values,
+            enabled: data[1],
+            running: data[2],
+        })
+    }
+
+    /// Apply an ioctl to the whole group.
+    fn ioctl(&self, ioctl: unsafe fn(c_int, c_uint) -> c_int) -> io::Result<()> {
+        // SAFETY: these ioctls take only the group flag as argument.
+        check(unsafe { ioctl(self.leader.as_raw_fd(), bindings::PERF_IOC_FLAG_GROUP) })
+    }
+}
+
+/// Open a counter for the calling thread on any CPU, in the group led
+/// by `group_fd`, or as a group leader if that is -1.
+fn open_event(attrs: &mut bindings::perf_event_attr, group_fd: c_int) -> io::Result<File> {
+    // SAFETY: `attrs` is a valid `perf_event_attr`, and the returned file
+    // descriptor is owned by nothing else.
+    unsafe {
+        let fd = perf_event_open_sys::perf_event_open(
+            attrs,
+            0,
+            -1,
+            group_fd,
+            c_ulong::from(bindings::PERF_FLAG_FD_CLOEXEC),
+        );
+        check(fd)?;
+        Ok(File::from_raw_fd(fd))
+    }
+}
+
+fn check(result: c_int) -> io::Result<()> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+impl PerfGroupMeasurement {
+    /// Create a new group measurement, reporting the given [`PerfMode`]
+    /// event to Criterion.
+    #[must_use]
+    pub fn new(primary: PerfMode) -> Self {
+        Self {
+            members: vec![Source::Event(primary.event())],
+            formatter: primary.formatter(),
+            group: PerThread::default(),
+            options: CounterOptions::default(),
+            multiplexing: Multiplexing::default(),
+            warned: Cell::new(false),
+            samples: GroupSamples::new(vec![primary.formatter().units]),
+        }
+    }
+
+    /// Add another event to the group. Its values are recorded
+    /// alongside the primary event, but not reported to Criterion.
+    ///
+    /// As this changes the values recorded for each sample, the
+    /// measurement gets a new [`samples`](Self::samples) handle, which
+    /// is not shared with clones made before.
+    #[must_use]
+    pub fn member(mut self, mode: PerfMode) -> Self {
+        self.members.push(Source::Event(mode.event()));
+        let mut labels = self.samples.labels();
+        labels.push(mode.formatter().units);
+        self.samples = GroupSamples::new(labels);
+        self.group = PerThread::default();
+        self
+    }
+
+    /// Create a new group measurement like [`new`](Self::new) followed
+    /// by [`member`](Self::member) for each of `others`, after
+    /// verifying that the whole group can actually be opened.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`PerfError`] describing why the counter group could
+    /// not be opened or enabled.
+    pub fn try_new(primary: PerfMode, others: &[PerfMode]) -> Result<Self, PerfError> {
+        let measurement = others
+            .iter()
+            .fold(Self::new(primary), |measurement, &mode| {
+                measurement.member(mode)
+            });
+        measurement.with_group(GroupCounters::probe)?;
+        Ok(measurement)
+    }
+
+    /// Set whether the counters are inherited by threads created while
+    /// they are open, as for [`PerfMeasurement::inherit`].
+    ///
+    /// Some kernel versions refuse to inherit counters that belong to a
+    /// group. In that case, opening the group fails with
+    /// [`PerfError::InheritGroupUnsupported`].
+    ///
+    /// [`PerfMeasurement::inherit`]: crate::PerfMeasurement::inherit
+    #[must_use]
+    pub fn inherit(mut self, inherit: bool) -> Self {
+        self.options.inherit = inherit;
+        self.group = PerThread::default();
+        self
+    }
+
+    /// Set how samples are handled when the kernel multiplexes the
+    /// group, as for [`PerfMeasurement::multiplexing`]. The values of
+    /// all members are scaled by the fraction of the sample during
+    /// which the group was running, which is the same for every member.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the threshold of [`Multiplexing::Warn`] or
+    /// [`Multiplexing::Fail`] is not between 0 and 1.
+    ///
+    /// [`PerfMeasurement::multiplexing`]: crate::PerfMeasurement::multiplexing
+    #[must_use]
+    pub fn multiplexing(mut self, multiplexing: Multiplexing) -> Self {
+        self.multiplexing = multiplexing.validate();
+        self
+    }
+
+    /// Return a handle to the values recorded for every sample. The
+    /// handle remains usable after the measurement has been moved into
+    /// Criterion.
+    #[must_use]
+    pub fn samples(&self) -> GroupSamples {
+        self.samples.clone()
+    }
+
+    fn with_group<T>(
+        &self,
+        f: impl FnOnce(&mut GroupCounters) -> io::Result<T>,
+    ) -> Result<T, PerfError> {
+        self.group
+            .with(|| GroupCounters::open(&self.members, &self.options), f)
+    }
+}
+
+/// The intermediate value produced when a [`PerfGroupMeasurement`] or
+/// [`PerfRatioMeasurement`](crate::PerfRatioMeasurement) starts
+/// measuring a sample.
+pub struct GroupIntermediate(pub(crate) GroupCounts);
+
+impl Measurement for PerfGroupMeasurement {
+    type Intermediate = GroupIntermediate;
+    type Value = u64;
+
+    fn start(&self) -> Self::Intermediate {
+        GroupIntermediate(
+            self.with_group(GroupCounters::start)
+                .unwrap_or_else(|error| panic!("{error}")),
+        )
+    }
+
+    fn end(&self, intermediate: Self::Intermediate) -> Self::Value {
+        let values = self
+            .with_group(|group| group.stop(&intermediate.0))
+            .unwrap_or_else(|error| panic!("{error}"));
+        let values: Vec<u64> = values
+            .values
+            .iter()
+            .map(|&count| {
+                self.multiplexing
+                    .scale(count, values.enabled, values.running, &self.warned)
+            })
+            .collect();
+        let primary = values[0];
+        self.samples.record(values);
+        primary
+    }
+
+    fn add(&self, v1: &Self::Value, v2: &Self::Value) -> Self::Value {
+        v1 + v2
+    }
+
+    fn zero(&self) -> Self::Value {
+        0
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn to_f64(&self, val: &Self::Value) -> f64 {
+        *val as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &self.formatter
+    }
+}
+
+/// A shared handle to the counter values recorded by a
+/// [`PerfGroupMeasurement`], one entry per sample with the values of
+/// all group members in the order they were added.
+#[derive(Clone, Debug, Default)]
+pub struct GroupSamples(Arc<Mutex<SampleData>>);
+
+#[derive(Debug, Default)]
+struct SampleData {
+    labels: Vec<&'static str>,
+    samples: Vec<Vec<u64>>,
+}
+
+impl GroupSamples {
+    fn new(labels: Vec<&'static str>) -> Self {
+        Self(Arc::new(Mutex::new(SampleData {
+            labels,
+            samples: Vec::new(),
+        })))
+    }
+
+    fn record(&self, values: Vec<u64>) {
+        self.lock().samples.push(values);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SampleData> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The unit labels of the group members, in the order they were
+    /// added, starting with the primary event.
+    #[must_use]
+    pub fn labels(&self) -> Vec<&'static str> {
+        self.lock().labels.clone()
+    }
+
+    /// Remove and return the values recorded so far. Each sample holds
+    /// one value per group member, in the same order as
+    /// [`labels`](Self::labels). Note that the samples taken while
+    /// Criterion is warming up are included.
+    #[must_use]
+    pub fn take(&self) -> Vec<Vec<u64>> {
+        std::mem::take(&mut self.lock().samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ptr, thread};
+
+    use super::*;
+
+    /// The number of pages touched by `touch_pages`.
+    const PAGES: usize = 256;
+
+    /// Map fresh memory and write to every page of it, causing one page
+    /// fault per page.
+    fn touch_pages() {
+        // SAFETY: the mapping is private to this function.
+        unsafe {
+            let page = usize::try_from(libc::sysconf(libc::_SC_PAGESIZE)).unwrap();
+            let len = PAGES * page;
+            let memory = libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            assert_ne!(memory, libc::MAP_FAILED);
+            for offset in (0..len).step_by(page) {
+                memory.cast::<u8>().add(offset).write_volatile(1);
+            }
+            libc::munmap(memory, len);
+        }
+    }
+
+    #[test]
+    fn members_do_not_change_clones() {
+        let base = PerfGroupMeasurement::new(PerfMode::PageFaults);
+        let extended = base.clone().member(PerfMode::TaskClock);
+        assert_eq!(base.samples().labels(), ["page faults"]);
+        assert_eq!(extended.samples().labels(), ["page faults", "ns"]);
+        let start = base.start();
+        base.end(start);
+        assert_eq!(base.samples().take().len(), 1);
+        assert!(extended.samples().take().is_empty());
+    }
+
+    #[test]
+    fn inherited_samples_do_not_accumulate() {
+        let measurement = PerfGroupMeasurement::new(PerfMode::PageFaults)
+            .member(PerfMode::TaskClock)
+            .inherit(true);
+        let samples = measurement.samples();
+        for _ in 0..4 {
+            let start = measurement.start();
+            thread::spawn(touch_pages).join().unwrap();
+            measurement.end(start);
+        }
+        for sample in samples.take() {
+            assert!(
+                (PAGES as u64..2 * PAGES as u64).contains(&sample[0]),
+                "{sample:?}"
+            );
+        }
+    }
+}
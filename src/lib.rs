@@ -9,7 +9,7 @@
 //! use criterion_linux_perf::{PerfMeasurement, PerfMode};
 //!
 //! fn timeit(crit: &mut Criterion<PerfMeasurement>) {
-//!     crit.bench_function("String::new", |b| b.iter(|| String::new()));
+//!     crit.bench_function("String::new", |b| b.iter(String::new));
 //!     crit.bench_function("String::from", |b| b.iter(|| String::from("")));
 //! }
 //!
@@ -26,33 +26,102 @@
 #![deny(missing_docs)]
 #![deny(clippy::all, clippy::pedantic)]
 
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeSet,
+    fmt, fs, io, mem,
+    str::FromStr,
+    sync::{Mutex, Once},
+    thread::{self, ThreadId},
+    time::Instant,
+};
+
 use criterion::{
     measurement::{Measurement, ValueFormatter},
     Throughput,
 };
 use perf_event::{
-    events::{Event, Hardware},
-    Counter,
+    events::{Cache, Event, Hardware, Software},
+    CountAndTime, Counter,
 };
 
+mod affinity;
+mod config;
+mod error;
+mod group;
+mod pmu;
+mod ratio;
+mod raw;
+mod rdpmc;
+mod report;
+mod spec;
+
+use affinity::Affinity;
+pub use config::PerfConfig;
+pub use error::PerfError;
+pub use group::{GroupIntermediate, GroupSamples, PerfGroupMeasurement};
+pub use perf_event::events::{CacheOp, CacheResult, WhichCache};
+pub use pmu::CorePmu;
+pub use ratio::{PerfRatio, PerfRatioMeasurement};
+pub use raw::RawEvent;
+use rdpmc::UserPage;
+
 macro_rules! perf_mode {
-    ( $( $ident:ident = $event:expr => $unit:literal, )* ) => {
+    ( @scale ) => { Scale::Count };
+    ( @scale $scale:ident ) => { Scale::$scale };
+    ( @first $first:literal $( | $rest:literal )* ) => { $first };
+    ( $(
+        $ident:ident = $event:expr, $( $name:literal )|+ => $unit:literal $( in $scale:ident )?,
+    )* ) => {
         impl PerfMode {
+            /// The events other than the hardware cache events, in the
+            /// order they are declared.
+            const GENERIC: &'static [Self] = &[ $( Self::$ident, )* ];
+
+            /// The name `perf list` gives an event other than a hardware
+            /// cache event.
+            fn generic_perf_name(&self) -> Option<&'static str> {
+                match *self {
+                    $( Self::$ident => Some(perf_mode!(@first $( $name )|+)), )*
+                    Self::Cache { .. } => None,
+                }
+            }
+
+            /// Look up an event by the name `perf list` gives it, or one
+            /// of its aliases, ignoring case.
+            fn from_perf_name(name: &str) -> Option<Self> {
+                $(
+                    if [$( $name ),+].iter().any(|alias| alias.eq_ignore_ascii_case(name)) {
+                        return Some(Self::$ident);
+                    }
+                )*
+                cache_from_perf_name(name)
+            }
+
             fn event(&self) -> Event {
-                match self {
+                match *self {
                     $( Self::$ident => $event.into(), )*
+                    Self::Cache { which, operation, result } => {
+                        Cache { which, operation, result }.into()
+                    }
                 }
             }
 
              fn formatter(&self) -> PerfFormatter {
-                match self {
-                    $( Self::$ident => (
-                        PerfFormatter {
-                            units: $unit,
-                            throughput_bytes: concat!($unit, "/byte"),
-                            throughput_elements: concat!($unit, "/element"),
-                        }
+                match *self {
+                    $( Self::$ident => PerfFormatter::new(
+                        $unit,
+                        perf_mode!(@scale $( $scale )?),
                     ), )*
+                    Self::Cache { which, operation, result } => PerfFormatter::new(
+                        intern(format!(
+                            "{} {} {}",
+                            cache_name(which),
+                            cache_op_name(operation),
+                            cache_result_name(result),
+                        )),
+                        Scale::Count,
+                    ),
                 }
             }
         }
@@ -60,6 +129,20 @@ macro_rules! perf_mode {
 }
 
 /// The perf counter to measure when running a benchmark.
+///
+/// Events are displayed with, and can be parsed from, the names that
+/// `perf list` gives them:
+///
+/// ```
+/// use criterion_linux_perf::PerfMode;
+///
+/// let mode: PerfMode = "cycles".parse()?;
+/// assert_eq!(mode, PerfMode::Cycles);
+/// assert_eq!(mode.to_string(), "cpu-cycles");
+/// let misses: PerfMode = "L1-dcache-load-misses".parse()?;
+/// assert_eq!(misses.to_string(), "L1-dcache-load-misses");
+/// # Ok::<(), criterion_linux_perf::PerfError>(())
+/// ```
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum PerfMode {
     /// The number of instructions retired. These can be affected by
@@ -81,17 +164,331 @@ pub enum PerfMode {
     /// The total number of CPU cycles elapsed. This is not affected by
     /// CPU frequency scaling.
     RefCycles,
+    /// The time the task was running on a CPU, in nanoseconds.
+    TaskClock,
+    /// The time elapsed on the CPU clock while the task was running,
+    /// in nanoseconds.
+    CpuClock,
+    /// The number of page faults.
+    PageFaults,
+    /// The number of minor page faults, which were resolved without
+    /// requiring I/O.
+    MinorFaults,
+    /// The number of major page faults, which required I/O to resolve.
+    MajorFaults,
+    /// The number of context switches. This is always counted in the
+    /// kernel, where the scheduler records it; see [`Privilege`].
+    ContextSwitches,
+    /// The number of times the task migrated to another CPU. This is
+    /// always counted in the kernel, where the scheduler records it;
+    /// see [`Privilege`].
+    CpuMigrations,
+    /// The number of alignment faults that required kernel
+    /// intervention. These never happen on x86-64 or ARM.
+    AlignmentFaults,
+    /// The number of instruction emulation faults.
+    EmulationFaults,
+    /// The number of hardware cache events, such as L1 data cache read
+    /// misses or last-level cache write accesses.
+    Cache {
+        /// The cache to observe.
+        which: WhichCache,
+        /// The type of cache operation to count.
+        operation: CacheOp,
+        /// Whether to count all accesses or only misses.
+        result: CacheResult,
+    },
 }
 
 perf_mode! {
-    Instructions = Hardware::INSTRUCTIONS => "instructions",
-    Cycles = Hardware::CPU_CYCLES => "cycles",
-    Branches = Hardware::BRANCH_INSTRUCTIONS => "branches",
-    BranchMisses = Hardware::BRANCH_MISSES => "branch misses",
-    CacheRefs = Hardware::CACHE_REFERENCES => "cache refs",
-    CacheMisses = Hardware::CACHE_MISSES => "cache misses",
-    BusCycles = Hardware::BUS_CYCLES => "bus cycles",
-    RefCycles = Hardware::REF_CPU_CYCLES => "cycles",
+    Instructions = Hardware::INSTRUCTIONS, "instructions" => "instructions",
+    Cycles = Hardware::CPU_CYCLES, "cpu-cycles" | "cycles" => "cycles",
+    Branches = Hardware::BRANCH_INSTRUCTIONS, "branch-instructions" | "branches" => "branches",
+    BranchMisses = Hardware::BRANCH_MISSES, "branch-misses" => "branch misses",
+    CacheRefs = Hardware::CACHE_REFERENCES, "cache-references" => "cache refs",
+    CacheMisses = Hardware::CACHE_MISSES, "cache-misses" => "cache misses",
+    BusCycles = Hardware::BUS_CYCLES, "bus-cycles" => "bus cycles",
+    RefCycles = Hardware::REF_CPU_CYCLES, "ref-cycles" => "ref cycles",
+    TaskClock = Software::TASK_CLOCK, "task-clock" => "ns" in Nanoseconds,
+    CpuClock = Software::CPU_CLOCK, "cpu-clock" => "ns" in Nanoseconds,
+    PageFaults = Software::PAGE_FAULTS, "page-faults" | "faults" => "page faults",
+    MinorFaults = Software::PAGE_FAULTS_MIN, "minor-faults" => "minor faults",
+    MajorFaults = Software::PAGE_FAULTS_MAJ, "major-faults" => "major faults",
+    ContextSwitches = Software::CONTEXT_SWITCHES, "context-switches" | "cs" => "context switches",
+    CpuMigrations = Software::CPU_MIGRATIONS, "cpu-migrations" | "migrations" => "migrations",
+    AlignmentFaults = Software::ALIGNMENT_FAULTS, "alignment-faults" => "alignment faults",
+    EmulationFaults = Software::EMULATION_FAULTS, "emulation-faults" => "emulation faults",
+}
+
+fn cache_name(which: WhichCache) -> &'static str {
+    match which {
+        WhichCache::L1D => "L1D",
+        WhichCache::L1I => "L1I",
+        WhichCache::LL => "LLC",
+        WhichCache::DTLB => "dTLB",
+        WhichCache::ITLB => "iTLB",
+        WhichCache::BPU => "BPU",
+        WhichCache::NODE => "node",
+    }
+}
+
+fn cache_op_name(operation: CacheOp) -> &'static str {
+    match operation {
+        CacheOp::READ => "read",
+        CacheOp::WRITE => "write",
+        CacheOp::PREFETCH => "prefetch",
+    }
+}
+
+fn cache_result_name(result: CacheResult) -> &'static str {
+    match result {
+        CacheResult::ACCESS => "accesses",
+        CacheResult::MISS => "misses",
+    }
+}
+
+const CACHES: [WhichCache; 7] = [
+    WhichCache::L1D,
+    WhichCache::L1I,
+    WhichCache::LL,
+    WhichCache::DTLB,
+    WhichCache::ITLB,
+    WhichCache::BPU,
+    WhichCache::NODE,
+];
+const CACHE_OPS: [CacheOp; 3] = [CacheOp::READ, CacheOp::WRITE, CacheOp::PREFETCH];
+const CACHE_RESULTS: [CacheResult; 2] = [CacheResult::ACCESS, CacheResult::MISS];
+const ALL_MODES: usize =
+    PerfMode::GENERIC.len() + CACHES.len() * CACHE_OPS.len() * CACHE_RESULTS.len();
+
+impl PerfMode {
+    /// Every event, starting with the generic hardware and software
+    /// events in the order they are declared, followed by every
+    /// combination of cache, operation and result of the hardware cache
+    /// events.
+    pub const ALL: [Self; ALL_MODES] = all_modes();
+}
+
+const fn all_modes() -> [PerfMode; ALL_MODES] {
+    let mut modes = [PerfMode::Instructions; ALL_MODES];
+    let mut i = 0;
+    while i < PerfMode::GENERIC.len() {
+        modes[i] = PerfMode::GENERIC[i];
+        i += 1;
+    }
+    let mut c = 0;
+    while c < CACHES.len() {
+        let mut o = 0;
+        while o < CACHE_OPS.len() {
+            let mut r = 0;
+            while r < CACHE_RESULTS.len() {
+                modes[i] = PerfMode::Cache {
+                    which: CACHES[c],
+                    operation: CACHE_OPS[o],
+                    result: CACHE_RESULTS[r],
+                };
+                i += 1;
+                r += 1;
+            }
+            o += 1;
+        }
+        c += 1;
+    }
+    modes
+}
+
+impl fmt::Display for PerfMode {
+    /// Write the name `perf list` gives the event, such as
+    /// `cpu-cycles` or `L1-dcache-load-misses`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Cache {
+                which,
+                operation,
+                result,
+            } => f.write_str(&cache_perf_name(which, operation, result)),
+            _ => f.write_str(self.generic_perf_name().unwrap_or_default()),
+        }
+    }
+}
+
+impl FromStr for PerfMode {
+    type Err = PerfError;
+
+    /// Parse the name `perf list` gives an event, or one of its
+    /// aliases, ignoring case. For example, `cpu-cycles` and `cycles`
+    /// both name [`PerfMode::Cycles`], and `LLC-load-misses` names the
+    /// last-level cache read misses.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::from_perf_name(name.trim())
+            .ok_or_else(|| raw::invalid(format!("unknown perf event `{name}`")))
+    }
+}
+
+/// The name `perf list` gives a hardware cache event, such as
+/// `L1-dcache-load-misses` or `LLC-stores`.
+fn cache_perf_name(which: WhichCache, operation: CacheOp, result: CacheResult) -> String {
+    let cache = match which {
+        WhichCache::L1D => "L1-dcache",
+        WhichCache::L1I => "L1-icache",
+        WhichCache::LL => "LLC",
+        WhichCache::DTLB => "dTLB",
+        WhichCache::ITLB => "iTLB",
+        WhichCache::BPU => "branch",
+        WhichCache::NODE => "node",
+    };
+    let (operation, operations) = match operation {
+        CacheOp::READ => ("load", "loads"),
+        CacheOp::WRITE => ("store", "stores"),
+        CacheOp::PREFETCH => ("prefetch", "prefetches"),
+    };
+    match result {
+        CacheResult::ACCESS => format!("{cache}-{operations}"),
+        CacheResult::MISS => format!("{cache}-{operation}-misses"),
+    }
+}
+
+fn cache_from_perf_name(name: &str) -> Option<PerfMode> {
+    PerfMode::ALL.into_iter().find(|mode| match *mode {
+        PerfMode::Cache {
+            which,
+            operation,
+            result,
+        } => cache_perf_name(which, operation, result).eq_ignore_ascii_case(name),
+        _ => false,
+    })
+}
+
+/// What to measure instead when the requested perf event cannot be
+/// opened, as used by [`PerfMeasurement::with_fallback`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum Fallback {
+    /// Measure elapsed wall-clock time.
+    WallTime,
+    /// Measure the time the task was actually running on a CPU, using
+    /// the `task-clock` software event. If that event cannot be opened
+    /// either, wall-clock time is measured instead.
+    TaskClock,
+}
+
+static FALLBACK_WARNING: Once = Once::new();
+
+/// How [`PerfMeasurement`] handles samples during which the kernel
+/// multiplexed the counter with others, because more events were
+/// requested than the PMU has counter registers.
+///
+/// A multiplexed counter only counts while it is scheduled on the PMU,
+/// so its value is always scaled by the ratio of the time the counter
+/// was enabled to the time it was actually running. The policies differ
+/// in how they treat samples where that estimate rests on too little
+/// data: the threshold is the minimum fraction, between 0 and 1, of the
+/// enabled time that the counter must have been running.
+/// A counter that never ran during a sample reads 0, which prints a
+/// warning, or fails the benchmark with [`Fail`](Self::Fail).
+///
+/// This does not apply to counters opened on specific core PMUs of a
+/// hybrid CPU, as described for [`CorePmu`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Multiplexing {
+    /// Scale the count without further checks. This is the default.
+    #[default]
+    Scale,
+    /// Scale the count, and print a warning on standard error the first
+    /// time a sample falls below the threshold.
+    Warn(f64),
+    /// Scale the count, and fail the benchmark by panicking when a
+    /// sample falls below the threshold.
+    Fail(f64),
+}
+
+impl Multiplexing {
+    /// Panic if the threshold is not a fraction between 0 and 1.
+    fn validate(self) -> Self {
+        if let Self::Warn(threshold) | Self::Fail(threshold) = self {
+            assert!(
+                (0.0..=1.0).contains(&threshold),
+                "multiplexing threshold {threshold} is not between 0 and 1"
+            );
+        }
+        self
+    }
+
+    /// Scale a count by the fraction of the sample during which the
+    /// counter was actually running, applying the policy. `warned`
+    /// records whether the warning has already been printed.
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    fn scale(self, count: u64, enabled: u64, running: u64, warned: &Cell<bool>) -> u64 {
+        if running == enabled {
+            return count;
+        }
+        if running == 0 {
+            let message = "perf counter was multiplexed and never ran during a sample";
+            if let Self::Fail(_) = self {
+                panic!("{message}");
+            }
+            if !warned.replace(true) {
+                eprintln!("warning: {message}, so it is reported as 0");
+            }
+            return 0;
+        }
+        let fraction = running as f64 / enabled as f64;
+        match self {
+            Self::Scale => (),
+            Self::Warn(threshold) => {
+                if fraction < threshold && !warned.replace(true) {
+                    eprintln!(
+                        "warning: perf counter was multiplexed and only ran for {:.1}% of a sample",
+                        fraction * 100.0
+                    );
+                }
+            }
+            Self::Fail(threshold) => assert!(
+                fraction >= threshold,
+                "perf counter was multiplexed and only ran for {:.1}% of a sample",
+                fraction * 100.0
+            ),
+        }
+        (u128::from(count) * u128::from(enabled) / u128::from(running)) as u64
+    }
+}
+
+/// How [`PerfMeasurement`] handles samples during which the measuring
+/// thread migrated to another CPU.
+///
+/// Migrations are detected with a `cpu-migrations` software counter
+/// that covers each sample of the thread that measures it. Criterion
+/// offers no way to drop a sample, so a sample with a migration can
+/// only be reported or made to fail the benchmark.
+///
+/// The kernel records migrations in kernel context, so the counter
+/// always counts in the kernel, whatever the [`Privilege`] of the
+/// measurement. Opening it therefore needs `kernel.perf_event_paranoid`
+/// to be 1 or lower, or the `CAP_PERFMON` capability.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum Migrations {
+    /// Do not check for migrations. This is the default.
+    #[default]
+    Ignore,
+    /// Print a warning on standard error the first time a sample
+    /// includes a migration.
+    Warn,
+    /// Fail the benchmark by panicking when a sample includes a
+    /// migration.
+    Fail,
 }
 
 /// The measurement type to be used with `Criterion::with_measurement()`.
@@ -100,8 +497,271 @@ perf_mode! {
 /// [`PerfMode`]`::Instructions`.
 #[derive(Clone)]
 pub struct PerfMeasurement {
-    event: Event,
+    source: Source,
     formatter: PerfFormatter,
+    counter: PerThread<ThreadCounters>,
+    options: CounterOptions,
+    scope: Scope,
+    core_pmu: CorePmu,
+    pin: Option<usize>,
+    multiplexing: Multiplexing,
+    migrations: Migrations,
+    subtract_overhead: bool,
+    rdpmc: bool,
+    warned: Cell<bool>,
+    migration_warned: Cell<bool>,
+}
+
+/// The counters a [`PerfMeasurement`] has opened on one thread.
+struct ThreadCounters {
+    counters: Vec<Counter>,
+    /// The metadata pages through which the counters are read from user
+    /// space, if that is enabled.
+    pages: Vec<UserPage>,
+    /// Counts the migrations of the measuring thread, if they are
+    /// checked.
+    migrations: Option<Counter>,
+    /// The median count of an empty sample, once it has been measured.
+    overhead: Option<u64>,
+    /// Keeps the measuring thread pinned while the counters are open.
+    _affinity: Option<Affinity>,
+}
+
+/// The number of empty samples measured to calibrate the overhead of
+/// the counters.
+const CALIBRATION_SAMPLES: usize = 1000;
+
+/// What a [`PerfMeasurement`] measures.
+#[derive(Clone)]
+enum Source {
+    /// A generic event known to `perf-event`.
+    Event(Event),
+    /// A raw PMU event.
+    Raw(RawEvent),
+    /// Elapsed wall-clock time, used as a fallback.
+    WallTime,
+}
+
+impl Source {
+    fn builder<'a>(&self, options: &CounterOptions) -> perf_event::Builder<'a> {
+        let mut builder = match self {
+            Self::Event(event) => perf_event::Builder::new().kind(event.clone()),
+            Self::Raw(raw) => {
+                let mut builder = perf_event::Builder::new();
+                raw.configure(&mut builder);
+                builder
+            }
+            Self::WallTime => unreachable!("wall-clock measurements have no counter"),
+        };
+        options.configure(&mut builder);
+        if self.is_scheduler_event() {
+            builder.exclude_kernel(false);
+        }
+        builder
+    }
+
+    /// Whether this is a software event that the scheduler records in
+    /// kernel context, which a counter excluding the kernel never sees.
+    fn is_scheduler_event(&self) -> bool {
+        matches!(
+            self,
+            Self::Event(Event::Software(
+                Software::CONTEXT_SWITCHES | Software::CPU_MIGRATIONS
+            ))
+        )
+    }
+}
+
+/// Which privilege levels a counter observes.
+///
+/// Context switches and CPU migrations are recorded by the scheduler in
+/// the kernel, so [`PerfMode::ContextSwitches`] and
+/// [`PerfMode::CpuMigrations`] always count in the kernel, whatever the
+/// privilege. Opening them therefore needs `kernel.perf_event_paranoid`
+/// to be 1 or lower, or the `CAP_PERFMON` capability.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Privilege {
+    /// Count only events in user space. This gives the most
+    /// deterministic counts, as it excludes the work done by the kernel
+    /// in system calls and interrupts. This is the default.
+    #[default]
+    User,
+    /// Count only events in the kernel.
+    Kernel,
+    /// Count events at all privilege levels, including the hypervisor.
+    All,
+}
+
+/// Which threads or CPUs a [`PerfMeasurement`] counts events on.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum Scope {
+    /// Count the thread that measures each sample. This is the default.
+    #[default]
+    CallingThread,
+    /// Count every thread of the given processes, such as a server or
+    /// a worker pool that the benchmark hands its work off to.
+    ///
+    /// The threads are listed from `/proc/<pid>/task` when the counters
+    /// are opened, on the first sample measured on each thread.
+    /// Threads created after that are not observed.
+    Processes(Vec<u32>),
+    /// Count the given threads, identified by their kernel thread IDs.
+    Threads(Vec<u32>),
+    /// Count every process and thread running on the given CPUs, or on
+    /// every online CPU if `cpus` is `None`. This includes work done
+    /// on behalf of the benchmark in interrupt and softirq context on
+    /// other CPUs, which is only counted when the kernel is included in
+    /// the [`Privilege`] levels.
+    ///
+    /// System-wide counting requires `kernel.perf_event_paranoid` to be
+    /// 0 or lower, or the `CAP_PERFMON` capability.
+    SystemWide {
+        /// The CPUs to count on.
+        cpus: Option<Vec<usize>>,
+    },
+}
+
+/// List the IDs of the threads of a process.
+fn process_threads(pid: u32) -> io::Result<Vec<u32>> {
+    fs::read_dir(format!("/proc/{pid}/task"))?
+        .map(|entry| {
+            let name = entry?.file_name();
+            name.to_str()
+                .and_then(|name| name.parse().ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid thread ID"))
+        })
+        .collect()
+}
+
+/// What a single counter observes.
+#[derive(Clone, Copy)]
+enum Target {
+    /// The thread that opened the counter.
+    CallingThread,
+    /// Another thread.
+    Thread(i32),
+    /// Every process and thread running on a CPU.
+    Cpu(usize),
+}
+
+impl Target {
+    fn thread(tid: u32) -> Result<Self, PerfError> {
+        i32::try_from(tid).map(Self::Thread).map_err(|_| {
+            PerfError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid thread ID {tid}"),
+            ))
+        })
+    }
+}
+
+const ONLINE_CPUS: &str = "/sys/devices/system/cpu/online";
+
+/// List the online CPUs, as given in `/sys/devices/system/cpu/online`
+/// in the form `0-3,6,8-11`.
+fn online_cpus() -> Result<Vec<usize>, PerfError> {
+    let list = fs::read_to_string(ONLINE_CPUS).map_err(|error| {
+        PerfError::Io(io::Error::new(
+            error.kind(),
+            format!("cannot list the online CPUs: {error}"),
+        ))
+    })?;
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',') {
+        let (low, high) = range.split_once('-').unwrap_or((range, range));
+        let (low, high) = low
+            .parse::<usize>()
+            .ok()
+            .zip(high.parse::<usize>().ok())
+            .ok_or_else(|| {
+                PerfError::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid CPU list `{}` in {ONLINE_CPUS}", list.trim()),
+                ))
+            })?;
+        cpus.extend(low..=high);
+    }
+    Ok(cpus)
+}
+
+/// The settings applied to every counter opened by a measurement.
+#[derive(Clone, Debug)]
+#[allow(clippy::struct_excessive_bools)]
+struct CounterOptions {
+    exclude_user: bool,
+    exclude_kernel: bool,
+    exclude_hv: bool,
+    exclude_idle: bool,
+    inherit: bool,
+}
+
+impl Default for CounterOptions {
+    fn default() -> Self {
+        let mut options = Self {
+            exclude_user: false,
+            exclude_kernel: false,
+            exclude_hv: false,
+            exclude_idle: false,
+            inherit: false,
+        };
+        options.set_privilege(Privilege::default());
+        options
+    }
+}
+
+impl CounterOptions {
+    fn set_privilege(&mut self, privilege: Privilege) {
+        (self.exclude_user, self.exclude_kernel, self.exclude_hv) = match privilege {
+            Privilege::User => (false, true, true),
+            Privilege::Kernel => (true, false, true),
+            Privilege::All => (false, false, false),
+        };
+    }
+
+    fn configure(&self, builder: &mut perf_event::Builder) {
+        builder
+            .exclude_user(self.exclude_user)
+            .exclude_kernel(self.exclude_kernel)
+            .exclude_hv(self.exclude_hv)
+            .exclude_idle(self.exclude_idle)
+            .inherit(self.inherit);
+    }
+}
+
+/// A counter (or group of counters) that is opened lazily and then
+/// reused for every sample measured on the thread that opened it.
+/// Clones start out empty, as the underlying file descriptors cannot be
+/// shared.
+struct PerThread<T>(RefCell<Option<(ThreadId, T)>>);
+
+impl<T> Default for PerThread<T> {
+    fn default() -> Self {
+        Self(RefCell::new(None))
+    }
+}
+
+impl<T> Clone for PerThread<T> {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl<T> PerThread<T> {
+    /// Run `f` on the value for the current thread, calling `open` to
+    /// create it first if this thread has not yet done so.
+    fn with<R>(
+        &self,
+        open: impl FnOnce() -> Result<T, PerfError>,
+        f: impl FnOnce(&mut T) -> io::Result<R>,
+    ) -> Result<R, PerfError> {
+        let mut slot = self.0.borrow_mut();
+        let thread = thread::current().id();
+        if !matches!(&*slot, Some((id, _)) if *id == thread) {
+            *slot = Some((thread, open()?));
+        }
+        let (_, value) = slot.as_mut().expect("value was just opened");
+        Ok(f(value)?)
+    }
 }
 
 impl Default for PerfMeasurement {
@@ -114,28 +774,668 @@ impl PerfMeasurement {
     /// Create a new measurement, using the given [`PerfMode`] event.
     #[must_use]
     pub fn new(mode: PerfMode) -> Self {
-        let event = mode.event();
-        let formatter = mode.formatter();
-        Self { event, formatter }
+        Self::from_source(Source::Event(mode.event()), mode.formatter())
+    }
+
+    fn from_source(source: Source, formatter: PerfFormatter) -> Self {
+        Self {
+            source,
+            formatter,
+            counter: PerThread::default(),
+            options: CounterOptions::default(),
+            scope: Scope::default(),
+            core_pmu: CorePmu::default(),
+            pin: None,
+            multiplexing: Multiplexing::default(),
+            migrations: Migrations::default(),
+            subtract_overhead: false,
+            rdpmc: false,
+            warned: Cell::new(false),
+            migration_warned: Cell::new(false),
+        }
+    }
+
+    /// Create a new measurement of a raw event on the core CPU PMU,
+    /// using the given model-specific `config` value. The `label` is
+    /// used as the unit when reporting results.
+    #[must_use]
+    pub fn raw(config: u64, label: &str) -> Self {
+        Self::raw_event(RawEvent::new(config), label)
+    }
+
+    /// Create a new measurement of the given [`RawEvent`], which may
+    /// be on any PMU. The `label` is used as the unit when reporting
+    /// results.
+    #[must_use]
+    pub fn raw_event(event: RawEvent, label: &str) -> Self {
+        Self::from_source(
+            Source::Raw(event),
+            PerfFormatter::new(intern(label.to_owned()), Scale::Count),
+        )
+    }
+
+    /// Create a new measurement, using the given [`PerfMode`] event,
+    /// after verifying that a counter for the event can actually be
+    /// opened.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`PerfError`] describing why the probe counter could
+    /// not be opened or enabled, for example when the
+    /// `kernel.perf_event_paranoid` setting forbids access or the CPU
+    /// does not support the event.
+    pub fn try_new(mode: PerfMode) -> Result<Self, PerfError> {
+        let measurement = Self::new(mode);
+        measurement.probe()?;
+        Ok(measurement)
+    }
+
+    /// Create a new measurement, using the given [`PerfMode`] event if
+    /// a counter for it can be opened, or measuring time as specified by
+    /// `fallback` otherwise.
+    ///
+    /// When the fallback is used, a warning is printed once on standard
+    /// error and the reported units change to time units, so that
+    /// reports never present time as if it were the requested event.
+    #[must_use]
+    pub fn with_fallback(mode: PerfMode, fallback: Fallback) -> Self {
+        Self::new(mode).or_fallback(fallback)
+    }
+
+    /// Check that a counter for the event can be opened, and measure
+    /// time as specified by `fallback` otherwise, keeping the other
+    /// settings.
+    fn or_fallback(self, fallback: Fallback) -> Self {
+        let Err(error) = self.probe() else {
+            return self;
+        };
+        let task_clock = match fallback {
+            Fallback::TaskClock => Some(self.with_source(
+                Source::Event(PerfMode::TaskClock.event()),
+                PerfMode::TaskClock.formatter(),
+            ))
+            .filter(|task_clock| task_clock.probe().is_ok()),
+            Fallback::WallTime => None,
+        };
+        let measurement =
+            task_clock.unwrap_or_else(|| self.with_source(Source::WallTime, PerfFormatter::time()));
+        FALLBACK_WARNING.call_once(|| {
+            eprintln!(
+                "warning: cannot measure {}: {error}; measuring {} instead",
+                self.formatter.units,
+                measurement.description()
+            );
+        });
+        measurement
+    }
+
+    /// A copy of this measurement with its settings, measuring
+    /// something else.
+    fn with_source(&self, source: Source, formatter: PerfFormatter) -> Self {
+        Self {
+            source,
+            formatter: PerfFormatter {
+                units: formatter.units,
+                scale: formatter.scale,
+                ..self.formatter
+            },
+            ..self.clone()
+        }
+    }
+
+    /// Set how samples are handled when the kernel multiplexes the
+    /// counter. The default is [`Multiplexing::Scale`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the threshold of [`Multiplexing::Warn`] or
+    /// [`Multiplexing::Fail`] is not between 0 and 1.
+    #[must_use]
+    pub fn multiplexing(mut self, multiplexing: Multiplexing) -> Self {
+        self.multiplexing = multiplexing.validate();
+        self
+    }
+
+    /// Set which core PMU of a hybrid CPU counts the event. The default
+    /// is [`CorePmu::Any`].
+    ///
+    /// When a PMU is named, its name is added to the reported unit of
+    /// hardware and cache events, as in `instructions (cpu_core)`.
+    #[must_use]
+    pub fn core_pmu(mut self, core_pmu: CorePmu) -> Self {
+        if matches!(&self.source, Source::Event(event) if pmu::is_core_event(event)) {
+            let mut units = self.formatter.units;
+            if let CorePmu::Named(name) = &self.core_pmu {
+                units = units.strip_suffix(&format!(" ({name})")).unwrap_or(units);
+            }
+            if let CorePmu::Named(name) = &core_pmu {
+                units = intern(format!("{units} ({name})"));
+            }
+            self.formatter.units = units;
+        }
+        self.core_pmu = core_pmu;
+        self.counter = PerThread::default();
+        self
+    }
+
+    /// Set the unit that values are reported in, such as `"L3 misses"`,
+    /// instead of the one named after the event. The unit is still
+    /// given SI prefixes, and the name of the PMU selected with
+    /// [`core_pmu`](Self::core_pmu) is still added to it.
+    #[must_use]
+    pub fn with_label(mut self, label: String) -> Self {
+        self.formatter.units = intern(label);
+        let core_pmu = mem::take(&mut self.core_pmu);
+        self.core_pmu(core_pmu)
+    }
+
+    /// Pin the thread that measures the benchmark to the given CPU, so
+    /// that its counts are not skewed by moving between cores, such as
+    /// the performance and efficiency cores of a hybrid CPU.
+    ///
+    /// The thread is pinned when the counters are opened, on the first
+    /// sample it measures, and its previous CPU affinity is restored
+    /// when the measurement is dropped.
+    #[must_use]
+    pub fn pin_to_cpu(mut self, cpu: usize) -> Self {
+        self.pin = Some(cpu);
+        self.counter = PerThread::default();
+        self
+    }
+
+    /// Set how samples are handled when the measuring thread migrates
+    /// to another CPU during a sample. The default is
+    /// [`Migrations::Ignore`].
+    ///
+    /// This is mostly useful together with
+    /// [`pin_to_cpu`](Self::pin_to_cpu), to verify that the pinning
+    /// held.
+    #[must_use]
+    pub fn migrations(mut self, migrations: Migrations) -> Self {
+        self.migrations = migrations;
+        self.counter = PerThread::default();
+        self
+    }
+
+    /// Set whether the overhead of the counters is subtracted from every
+    /// sample, saturating at zero.
+    ///
+    /// Enabling, disabling and reading the counters adds a roughly
+    /// constant number of events to each sample, which matters for
+    /// benchmarks of very small functions. When this is set, the
+    /// overhead is calibrated as soon as the counters are opened, by
+    /// measuring many empty samples, and their median count is
+    /// subtracted. The default is not to subtract the overhead.
+    #[must_use]
+    pub fn subtract_overhead(mut self, subtract: bool) -> Self {
+        self.subtract_overhead = subtract;
+        self.counter = PerThread::default();
+        self
+    }
+
+    /// Set whether the counters are read from user space where the
+    /// kernel permits it, with the `rdpmc` instruction on x86-64 or
+    /// `mrs` on aarch64, instead of with a `read` system call. The
+    /// default is to use system calls.
+    ///
+    /// This reduces the overhead of each sample considerably, as the
+    /// counters are then also left enabled between samples instead of
+    /// being toggled with system calls. It only applies to the
+    /// [`Scope::CallingThread`] scope. Whenever the kernel does not
+    /// permit a user space read (`cap_user_rdpmc` is not set, as for
+    /// software events) or the counter is not currently scheduled on
+    /// the PMU, that read falls back to the system call. On aarch64,
+    /// user space access must also be enabled with the
+    /// `kernel.perf_user_access` sysctl.
+    ///
+    /// When the counters are multiplexed, the correction for it is
+    /// approximate, as the times used are those of the last time the
+    /// kernel updated the counter's metadata.
+    #[must_use]
+    pub fn rdpmc(mut self, rdpmc: bool) -> Self {
+        self.rdpmc = rdpmc;
+        self.counter = PerThread::default();
+        self
+    }
+
+    /// Return the overhead of the counters for the current thread, as
+    /// the median count of many empty samples, calibrating it first if
+    /// that has not yet been done. This is zero for wall-clock
+    /// measurements.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`PerfError`] describing why the counters could not be
+    /// opened or operated.
+    pub fn overhead(&self) -> Result<u64, PerfError> {
+        match self.source {
+            Source::WallTime => Ok(0),
+            _ => self.with_counters(|counters| self.calibrate(counters)),
+        }
+    }
+
+    /// Set how values are displayed for benchmarks with a throughput.
+    /// The default is [`ThroughputStyle::EventsPerByte`].
+    #[must_use]
+    pub fn throughput_style(mut self, style: ThroughputStyle) -> Self {
+        self.formatter.throughput = style;
+        self
+    }
+
+    /// Set whether counts of less than one event per iteration are
+    /// shown per thousand or per million iterations instead, such as
+    /// `310 branch misses/1K iterations` rather than `0.31 branch
+    /// misses`. The unit always states the normalization. This has no
+    /// effect on time measurements, which are shown in smaller units of
+    /// time instead, or on benchmarks with a throughput. The default is
+    /// `false`.
+    #[must_use]
+    pub fn per_iterations(mut self, per_iterations: bool) -> Self {
+        self.formatter.per_iterations = per_iterations;
+        self
+    }
+
+    /// Set which privilege levels are counted. This sets all of the
+    /// `exclude_*` options except [`exclude_idle`](Self::exclude_idle).
+    /// The default is [`Privilege::User`].
+    ///
+    /// Counting kernel or hypervisor events usually requires
+    /// `kernel.perf_event_paranoid` to be set to 1 or lower.
+    #[must_use]
+    pub fn privilege(self, privilege: Privilege) -> Self {
+        self.configure(|options| options.set_privilege(privilege))
+    }
+
+    /// Set whether events in user space are excluded from the count.
+    #[must_use]
+    pub fn exclude_user(self, exclude: bool) -> Self {
+        self.configure(|options| options.exclude_user = exclude)
+    }
+
+    /// Set whether events in the kernel are excluded from the count.
+    #[must_use]
+    pub fn exclude_kernel(self, exclude: bool) -> Self {
+        self.configure(|options| options.exclude_kernel = exclude)
+    }
+
+    /// Set whether events in the hypervisor are excluded from the
+    /// count.
+    #[must_use]
+    pub fn exclude_hv(self, exclude: bool) -> Self {
+        self.configure(|options| options.exclude_hv = exclude)
+    }
+
+    /// Set whether events that occur while the CPU is idle are excluded
+    /// from the count. This only affects software events.
+    #[must_use]
+    pub fn exclude_idle(self, exclude: bool) -> Self {
+        self.configure(|options| options.exclude_idle = exclude)
+    }
+
+    /// Set whether the counter is inherited by threads created while it
+    /// is open, so that work the benchmarked code hands off to threads
+    /// it spawns is included in the count.
+    ///
+    /// Only threads created after the counter is opened are observed,
+    /// which happens on the first sample measured on each thread. Work
+    /// done on threads that already exist, such as a thread pool started
+    /// before the benchmark, is not counted.
+    #[must_use]
+    pub fn inherit(self, inherit: bool) -> Self {
+        self.configure(|options| options.inherit = inherit)
+    }
+
+    /// Set which threads or CPUs are counted. The default is
+    /// [`Scope::CallingThread`].
+    ///
+    /// When observing other threads, each sample counts the events of
+    /// all of them from the start to the end of the sample, whether or
+    /// not they were working on behalf of the benchmark.
+    #[must_use]
+    pub fn scope(mut self, scope: Scope) -> Self {
+        self.scope = scope;
+        self.counter = PerThread::default();
+        self
+    }
+
+    /// Count the events of every thread of the given processes instead
+    /// of the calling thread. This is a shorthand for
+    /// [`scope`](Self::scope) with [`Scope::Processes`].
+    #[must_use]
+    pub fn observe_pids(self, pids: &[u32]) -> Self {
+        self.scope(Scope::Processes(pids.to_vec()))
+    }
+
+    /// Count the events of the given threads instead of the calling
+    /// thread. This is a shorthand for [`scope`](Self::scope) with
+    /// [`Scope::Threads`].
+    #[must_use]
+    pub fn observe_threads(self, tids: &[u32]) -> Self {
+        self.scope(Scope::Threads(tids.to_vec()))
+    }
+
+    /// Change the counter options, closing any counter already opened
+    /// with the previous options.
+    fn configure(mut self, f: impl FnOnce(&mut CounterOptions)) -> Self {
+        f(&mut self.options);
+        self.counter = PerThread::default();
+        self
+    }
+
+    fn description(&self) -> &'static str {
+        match self.source {
+            Source::WallTime => "wall-clock time",
+            _ => "task clock",
+        }
+    }
+
+    /// Open the counters for this thread and check that they can be
+    /// enabled. The counters are kept open for use by later samples.
+    fn probe(&self) -> Result<(), PerfError> {
+        match self.source {
+            Source::WallTime => Ok(()),
+            _ => self.with_counters(|counters| {
+                counters.start()?;
+                counters.stop()?;
+                Ok(())
+            }),
+        }
+    }
+
+    /// Pin the current thread if requested, then open the counters.
+    fn open(&self) -> Result<ThreadCounters, PerfError> {
+        let affinity = self.pin.map(Affinity::pin).transpose()?;
+        // Migrations are recorded by the scheduler in the kernel, so the
+        // side counter must include the kernel whatever the privilege
+        // levels of the measured event are.
+        let migrations = match self.migrations {
+            Migrations::Ignore => None,
+            Migrations::Warn | Migrations::Fail => {
+                let mut builder = perf_event::Builder::new().kind(Software::CPU_MIGRATIONS);
+                builder.exclude_kernel(false).exclude_hv(false);
+                Some(builder.build()?)
+            }
+        };
+        let mut counters = self.open_counters()?;
+        let pages = if self.rdpmc && self.scope == Scope::CallingThread {
+            match counters.iter().map(UserPage::map).collect() {
+                Ok(pages) => {
+                    counters.iter_mut().try_for_each(Counter::enable)?;
+                    pages
+                }
+                Err(_) => Vec::new(),
+            }
+        } else {
+            Vec::new()
+        };
+        let mut counters = ThreadCounters {
+            counters,
+            pages,
+            migrations,
+            overhead: None,
+            _affinity: affinity,
+        };
+        if self.subtract_overhead {
+            self.calibrate(&mut counters)?;
+        }
+        Ok(counters)
+    }
+
+    /// Open one counter for each thread or CPU in the scope and each
+    /// selected core PMU.
+    fn open_counters(&self) -> Result<Vec<Counter>, PerfError> {
+        let pmus = self.core_pmu.types()?;
+        let mut counters = Vec::new();
+        for target in self.targets()? {
+            for &pmu in &pmus {
+                counters.push(self.open_counter(target, pmu)?);
+            }
+        }
+        Ok(counters)
+    }
+
+    /// List what the counters observe, as given by the scope.
+    fn targets(&self) -> Result<Vec<Target>, PerfError> {
+        match &self.scope {
+            Scope::CallingThread => Ok(vec![Target::CallingThread]),
+            Scope::Threads(tids) => tids.iter().map(|&tid| Target::thread(tid)).collect(),
+            Scope::Processes(pids) => {
+                let mut targets = Vec::new();
+                for &pid in pids {
+                    let tids = process_threads(pid).map_err(|error| {
+                        PerfError::Io(io::Error::new(
+                            error.kind(),
+                            format!("cannot list the threads of process {pid}: {error}"),
+                        ))
+                    })?;
+                    for tid in tids {
+                        targets.push(Target::thread(tid)?);
+                    }
+                }
+                Ok(targets)
+            }
+            Scope::SystemWide { cpus } => {
+                let cpus = match cpus {
+                    Some(cpus) => cpus.clone(),
+                    None => online_cpus()?,
+                };
+                Ok(cpus.into_iter().map(Target::Cpu).collect())
+            }
+        }
+    }
+
+    fn open_counter(&self, target: Target, pmu: Option<u32>) -> Result<Counter, PerfError> {
+        let mut builder = self.source.builder(&self.options);
+        if self.rdpmc {
+            rdpmc::request(&mut builder, &self.source);
+        }
+        if let (Source::Event(event), Some(pmu)) = (&self.source, pmu) {
+            pmu::select(&mut builder, event, pmu);
+        }
+        match target {
+            Target::CallingThread => Ok(builder.build()?),
+            Target::Thread(tid) => Ok(builder.observe_pid(tid).build()?),
+            Target::Cpu(cpu) => builder.any_pid().one_cpu(cpu).build().map_err(|error| {
+                match PerfError::from(error) {
+                    PerfError::PermissionDenied { paranoid, source } => {
+                        PerfError::SystemWidePermissionDenied { paranoid, source }
+                    }
+                    error => error,
+                }
+            }),
+        }
+    }
+
+    /// Run `f` on the counters for the current thread, opening them
+    /// first if this thread has not yet done so.
+    fn with_counters<T>(
+        &self,
+        f: impl FnOnce(&mut ThreadCounters) -> io::Result<T>,
+    ) -> Result<T, PerfError> {
+        self.counter.with(|| self.open(), f)
+    }
+
+    /// Apply the migration policy to a sample during which the thread
+    /// migrated `count` times.
+    fn check_migrations(&self, count: u64) {
+        if count == 0 {
+            return;
+        }
+        match self.migrations {
+            Migrations::Ignore => (),
+            Migrations::Warn => {
+                if !self.migration_warned.replace(true) {
+                    eprintln!(
+                        "warning: thread migrated to another CPU {count} times during a sample"
+                    );
+                }
+            }
+            Migrations::Fail => {
+                panic!("thread migrated to another CPU {count} times during a sample")
+            }
+        }
+    }
+}
+
+impl ThreadCounters {
+    /// Start a sample, returning the count and times of each counter at
+    /// its start.
+    ///
+    /// Counters read from user space are left enabled between samples,
+    /// as toggling them would cost the system calls that reading from
+    /// user space avoids. Otherwise, the counters are reset and enabled.
+    /// The migration counter is enabled first so that it covers the
+    /// whole sample.
+    fn start(&mut self) -> io::Result<Vec<CountAndTime>> {
+        if let Some(migrations) = &mut self.migrations {
+            migrations.reset()?;
+            migrations.enable()?;
+        }
+        if !self.pages.is_empty() {
+            return self.read();
+        }
+        self.counters.iter_mut().try_for_each(Counter::reset)?;
+        let starts = self.read()?;
+        self.counters.iter_mut().try_for_each(Counter::enable)?;
+        Ok(starts)
+    }
+
+    /// End a sample, returning the count and times of each counter and
+    /// the number of migrations at its end.
+    fn stop(&mut self) -> io::Result<(Vec<CountAndTime>, u64)> {
+        if self.pages.is_empty() {
+            self.counters.iter_mut().try_for_each(Counter::disable)?;
+        }
+        let ends = self.read()?;
+        let migrations = match &mut self.migrations {
+            Some(migrations) => {
+                migrations.disable()?;
+                migrations.read()?
+            }
+            None => 0,
+        };
+        Ok((ends, migrations))
+    }
+
+    /// Read every counter, from user space where possible and with a
+    /// system call otherwise.
+    fn read(&mut self) -> io::Result<Vec<CountAndTime>> {
+        let mut pages = self.pages.iter();
+        self.counters
+            .iter_mut()
+            .map(|counter| match pages.next().and_then(UserPage::read) {
+                Some(value) => Ok(value),
+                None => counter.read_count_and_time(),
+            })
+            .collect()
+    }
+}
+
+/// The intermediate value produced when [`PerfMeasurement`] starts
+/// measuring a sample.
+pub struct PerfIntermediate(Intermediate);
+
+enum Intermediate {
+    /// The count and times of each counter when the sample started.
+    /// Unlike the counts, the times are not cleared by a reset.
+    Counters(Vec<CountAndTime>),
+    WallTime(Instant),
+}
+
+impl PerfMeasurement {
+    /// Sum the counts of a sample, each scaled for multiplexing.
+    ///
+    /// A counter on one core PMU of a hybrid CPU is enabled but not
+    /// running while the thread is on a core of another type, which is
+    /// not multiplexing, so such counts are summed as they are.
+    fn total(&self, ends: &[CountAndTime], starts: &[CountAndTime]) -> u64 {
+        if self.per_core_pmu() {
+            return ends
+                .iter()
+                .zip(starts)
+                .map(|(end, start)| end.count - start.count)
+                .sum();
+        }
+        ends.iter()
+            .zip(starts)
+            .map(|(end, start)| {
+                self.scale_count(
+                    end.count - start.count,
+                    end.time_enabled - start.time_enabled,
+                    end.time_running - start.time_running,
+                )
+            })
+            .sum()
+    }
+
+    /// Whether the counters are opened on specific core PMUs.
+    fn per_core_pmu(&self) -> bool {
+        self.core_pmu != CorePmu::Any
+            && matches!(&self.source, Source::Event(event) if pmu::is_core_event(event))
+    }
+
+    /// Measure the overhead of the counters, if it has not yet been
+    /// measured, as the median count of many empty samples.
+    fn calibrate(&self, counters: &mut ThreadCounters) -> io::Result<u64> {
+        if let Some(overhead) = counters.overhead {
+            return Ok(overhead);
+        }
+        let mut counts = (0..CALIBRATION_SAMPLES)
+            .map(|_| {
+                let starts = counters.start()?;
+                let (ends, _) = counters.stop()?;
+                Ok(self.total(&ends, &starts))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        counts.sort_unstable();
+        let overhead = counts[counts.len() / 2];
+        counters.overhead = Some(overhead);
+        Ok(overhead)
+    }
+
+    /// Scale a count by the fraction of the sample during which the
+    /// counter was actually running, applying the multiplexing policy.
+    fn scale_count(&self, count: u64, enabled: u64, running: u64) -> u64 {
+        self.multiplexing
+            .scale(count, enabled, running, &self.warned)
     }
 }
 
 impl Measurement for PerfMeasurement {
-    type Intermediate = Counter;
+    type Intermediate = PerfIntermediate;
     type Value = u64;
 
     fn start(&self) -> Self::Intermediate {
-        let mut counter = perf_event::Builder::new()
-            .kind(self.event.clone())
-            .build()
-            .unwrap();
-        counter.enable().unwrap();
-        counter
+        PerfIntermediate(if let Source::WallTime = self.source {
+            Intermediate::WallTime(Instant::now())
+        } else {
+            let times = self
+                .with_counters(ThreadCounters::start)
+                .unwrap_or_else(|error| panic!("{error}"));
+            Intermediate::Counters(times)
+        })
     }
 
-    fn end(&self, mut counter: Self::Intermediate) -> Self::Value {
-        counter.disable().unwrap();
-        counter.read().unwrap()
+    #[allow(clippy::cast_possible_truncation)]
+    fn end(&self, intermediate: Self::Intermediate) -> Self::Value {
+        match intermediate.0 {
+            Intermediate::Counters(times) => {
+                let (ends, migrations, overhead) = self
+                    .with_counters(|counters| {
+                        let (ends, migrations) = counters.stop()?;
+                        Ok((ends, migrations, counters.overhead))
+                    })
+                    .unwrap_or_else(|error| panic!("{error}"));
+                self.check_migrations(migrations);
+                let count = self.total(&ends, &times);
+                if self.subtract_overhead {
+                    count.saturating_sub(overhead.unwrap_or(0))
+                } else {
+                    count
+                }
+            }
+            Intermediate::WallTime(start) => start.elapsed().as_nanos() as u64,
+        }
     }
 
     fn add(&self, v1: &Self::Value, v2: &Self::Value) -> Self::Value {
@@ -156,37 +1456,241 @@ impl Measurement for PerfMeasurement {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Copy)]
 struct PerfFormatter {
     units: &'static str,
-    throughput_bytes: &'static str,
-    throughput_elements: &'static str,
+    scale: Scale,
+    throughput: ThroughputStyle,
+    per_iterations: bool,
+}
+
+/// How the values of a measurement are scaled for display.
+#[derive(Clone, Copy)]
+enum Scale {
+    /// Plain event counts, displayed with the SI prefixes K, M, G or T
+    /// once they reach a thousand.
+    Count,
+    /// Nanoseconds, displayed as ps, ns, µs, ms or s in the same manner
+    /// as Criterion's `WallTime` measurement.
+    Nanoseconds,
+    /// A ratio between two counts, displayed unscaled and never divided
+    /// by the throughput.
+    Ratio,
+}
+
+/// How [`PerfMeasurement`] displays values for benchmarks with a
+/// throughput.
+///
+/// Either way, the amount of data or elements is shown with the binary
+/// prefixes KiB, MiB and GiB for `Throughput::Bytes`, the decimal
+/// prefixes KB, MB and GB for `Throughput::BytesDecimal`, and K, M and
+/// G for `Throughput::Elements`, picking the magnitude that suits the
+/// typical value.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ThroughputStyle {
+    /// Events per byte or element, such as `cycles/byte` or
+    /// `cycles/KiB`. This is the default.
+    #[default]
+    EventsPerByte,
+    /// Bytes or elements per event, such as `bytes/cycle` or
+    /// `KiB/K cycles`. Time measurements are shown as bytes or elements
+    /// per second, in the same manner as Criterion's `WallTime`
+    /// measurement.
+    BytesPerEvent,
+}
+
+/// The prefixes of counts, from one up, for inverted throughputs.
+const COUNT_PREFIXES: [&str; 4] = ["", "K ", "M ", "G "];
+
+impl PerfFormatter {
+    fn new(units: &'static str, scale: Scale) -> Self {
+        Self {
+            units,
+            scale,
+            throughput: ThroughputStyle::default(),
+            per_iterations: false,
+        }
+    }
+
+    fn time() -> Self {
+        Self::new("ns", Scale::Nanoseconds)
+    }
+}
+
+/// The singular of a plural unit, such as `cycle` for `cycles` or
+/// `branch miss (cpu_core)` for `branch misses (cpu_core)`. Units that
+/// are not plural, such as raw event names, are returned unchanged.
+fn singular(units: &str) -> String {
+    let (name, pmu) = match units.find(" (") {
+        Some(paren) => units.split_at(paren),
+        None => (units, ""),
+    };
+    let name = ["ches", "shes", "sses"]
+        .iter()
+        .find(|suffix| name.ends_with(*suffix))
+        .map_or_else(
+            || name.strip_suffix('s').unwrap_or(name),
+            |_| &name[..name.len() - 2],
+        );
+    format!("{name}{pmu}")
+}
+
+/// The amount of data or elements processed by a benchmark iteration,
+/// with the names of its magnitudes.
+struct Amounts {
+    count: f64,
+    base: f64,
+    one: &'static str,
+    many: &'static str,
+    prefixed: [&'static str; 3],
+}
+
+impl Amounts {
+    #[allow(clippy::cast_precision_loss)]
+    fn of(throughput: &Throughput) -> Self {
+        match *throughput {
+            Throughput::Bytes(count) => Self {
+                count: count as f64,
+                base: 1024.0,
+                one: "byte",
+                many: "bytes",
+                prefixed: ["KiB", "MiB", "GiB"],
+            },
+            Throughput::BytesDecimal(count) => Self {
+                count: count as f64,
+                base: 1000.0,
+                one: "byte",
+                many: "bytes",
+                prefixed: ["KB", "MB", "GB"],
+            },
+            Throughput::Elements(count) => Self {
+                count: count as f64,
+                base: 1000.0,
+                one: "element",
+                many: "elements",
+                prefixed: ["K elements", "M elements", "G elements"],
+            },
+        }
+    }
+
+    fn name(&self, magnitude: usize, plural: bool) -> &'static str {
+        match magnitude {
+            0 if plural => self.many,
+            0 => self.one,
+            _ => self.prefixed[magnitude - 1],
+        }
+    }
 }
 
 impl ValueFormatter for PerfFormatter {
-    fn scale_values(&self, _typical_value: f64, _values: &mut [f64]) -> &'static str {
-        self.units
+    fn scale_values(&self, typical_value: f64, values: &mut [f64]) -> &'static str {
+        match self.scale {
+            Scale::Ratio => self.units,
+            Scale::Count => {
+                let (factor, prefix) = if typical_value < 1.0 && self.per_iterations {
+                    let (factor, per) = if typical_value < 1e-3 {
+                        (1e6, "1M")
+                    } else {
+                        (1e3, "1K")
+                    };
+                    for val in values {
+                        *val *= factor;
+                    }
+                    return intern(format!("{}/{per} iterations", self.units));
+                } else if typical_value < 1e3 {
+                    return self.units;
+                } else if typical_value < 1e6 {
+                    (1e-3, "K")
+                } else if typical_value < 1e9 {
+                    (1e-6, "M")
+                } else if typical_value < 1e12 {
+                    (1e-9, "G")
+                } else {
+                    (1e-12, "T")
+                };
+                for val in values {
+                    *val *= factor;
+                }
+                intern(format!("{prefix} {}", self.units))
+            }
+            Scale::Nanoseconds => {
+                let (factor, units) = if typical_value < 1.0 {
+                    (1e3, "ps")
+                } else if typical_value < 1e3 {
+                    (1.0, "ns")
+                } else if typical_value < 1e6 {
+                    (1e-3, "µs")
+                } else if typical_value < 1e9 {
+                    (1e-6, "ms")
+                } else {
+                    (1e-9, "s")
+                };
+                for val in values {
+                    *val *= factor;
+                }
+                units
+            }
+        }
     }
 
     #[allow(clippy::cast_precision_loss)]
     fn scale_throughputs(
         &self,
-        _typical_value: f64,
+        typical_value: f64,
         throughput: &Throughput,
         values: &mut [f64],
     ) -> &'static str {
-        match throughput {
-            Throughput::Bytes(n) | Throughput::BytesDecimal(n) => {
-                for val in values {
-                    *val /= *n as f64;
+        if let Scale::Ratio = self.scale {
+            return self.units;
+        }
+        let amounts = Amounts::of(throughput);
+        match self.throughput {
+            ThroughputStyle::EventsPerByte => {
+                let mut magnitude = 0;
+                let mut factor = 1.0 / amounts.count;
+                while magnitude < 3 && typical_value * factor < 1.0 {
+                    magnitude += 1;
+                    factor *= amounts.base;
+                }
+                for val in values.iter_mut() {
+                    *val *= factor;
                 }
-                self.throughput_bytes
+                let formatter = PerfFormatter {
+                    per_iterations: false,
+                    ..*self
+                };
+                let units = formatter.scale_values(typical_value * factor, values);
+                intern(format!("{units}/{}", amounts.name(magnitude, false)))
             }
-            Throughput::Elements(n) => {
-                for val in values {
-                    *val /= *n as f64;
+            ThroughputStyle::BytesPerEvent => {
+                let mut factor = 1.0;
+                let mut typical = amounts.count / typical_value;
+                let per = if let Scale::Nanoseconds = self.scale {
+                    factor = 1e9;
+                    typical *= factor;
+                    "s".to_owned()
+                } else {
+                    let mut prefix = 0;
+                    while prefix < 3 && typical < 1.0 {
+                        prefix += 1;
+                        factor *= 1e3;
+                        typical *= 1e3;
+                    }
+                    match prefix {
+                        0 => singular(self.units),
+                        _ => format!("{}{}", COUNT_PREFIXES[prefix], self.units),
+                    }
+                };
+                let mut magnitude = 0;
+                while magnitude < 3 && typical >= amounts.base {
+                    magnitude += 1;
+                    factor /= amounts.base;
+                    typical /= amounts.base;
+                }
+                for val in values.iter_mut() {
+                    *val = amounts.count / *val * factor;
                 }
-                self.throughput_elements
+                intern(format!("{}/{per}", amounts.name(magnitude, true)))
             }
         }
     }
@@ -195,3 +1699,294 @@ impl ValueFormatter for PerfFormatter {
         self.units
     }
 }
+
+/// Return a `'static` copy of the given string, as required for the
+/// units returned by `ValueFormatter`. Each distinct string is leaked
+/// only once.
+fn intern(s: String) -> &'static str {
+    static INTERNED: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+    let mut interned = INTERNED
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    if let Some(s) = interned.get(s.as_str()) {
+        s
+    } else {
+        let s = Box::leak(s.into_boxed_str());
+        interned.insert(s);
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scale(formatter: PerfFormatter, typical: f64) -> (f64, &'static str) {
+        let mut values = [typical];
+        let units = formatter.scale_values(typical, &mut values);
+        (values[0], units)
+    }
+
+    fn throughput(
+        formatter: PerfFormatter,
+        typical: f64,
+        throughput: &Throughput,
+    ) -> (f64, &'static str) {
+        let mut values = [typical];
+        let units = formatter.scale_throughputs(typical, throughput, &mut values);
+        (values[0], units)
+    }
+
+    fn counts(units: &'static str) -> PerfFormatter {
+        PerfFormatter::new(units, Scale::Count)
+    }
+
+    fn inverted(formatter: PerfFormatter) -> PerfFormatter {
+        PerfFormatter {
+            throughput: ThroughputStyle::BytesPerEvent,
+            ..formatter
+        }
+    }
+
+    fn close(actual: (f64, &str), expected: (f64, &str)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-9 * expected.0.abs().max(1.0)
+                && actual.1 == expected.1,
+            "{actual:?} != {expected:?}"
+        );
+    }
+
+    #[test]
+    fn counts_take_si_prefixes() {
+        close(scale(counts("cycles"), 999.0), (999.0, "cycles"));
+        close(scale(counts("cycles"), 1500.0), (1.5, "K cycles"));
+        close(scale(counts("cycles"), 48.2e6), (48.2, "M cycles"));
+        close(scale(counts("cycles"), 3e9), (3.0, "G cycles"));
+        close(scale(counts("cycles"), 2e12), (2.0, "T cycles"));
+        close(scale(counts("cycles"), 0.31), (0.31, "cycles"));
+    }
+
+    #[test]
+    fn times_take_time_units() {
+        close(scale(PerfFormatter::time(), 0.5), (500.0, "ps"));
+        close(scale(PerfFormatter::time(), 12.0), (12.0, "ns"));
+        close(scale(PerfFormatter::time(), 1.5e3), (1.5, "µs"));
+        close(scale(PerfFormatter::time(), 2.5e6), (2.5, "ms"));
+        close(scale(PerfFormatter::time(), 3e9), (3.0, "s"));
+    }
+
+    #[test]
+    fn ratios_are_not_scaled() {
+        let ratio = PerfFormatter::new("IPC", Scale::Ratio);
+        close(scale(ratio, 2.5e6), (2.5e6, "IPC"));
+        close(
+            throughput(ratio, 2.5, &Throughput::Bytes(1024)),
+            (2.5, "IPC"),
+        );
+    }
+
+    #[test]
+    fn small_counts_per_iterations() {
+        let formatter = PerfFormatter {
+            per_iterations: true,
+            ..counts("branch misses")
+        };
+        close(
+            scale(formatter, 0.31),
+            (310.0, "branch misses/1K iterations"),
+        );
+        close(
+            scale(formatter, 0.0004),
+            (400.0, "branch misses/1M iterations"),
+        );
+        close(scale(formatter, 5.0), (5.0, "branch misses"));
+        close(scale(formatter, 5e3), (5.0, "K branch misses"));
+        close(
+            throughput(formatter, 0.5, &Throughput::Elements(1)),
+            (500.0, "branch misses/K elements"),
+        );
+    }
+
+    #[test]
+    fn events_per_byte() {
+        let cycles = counts("cycles");
+        close(
+            throughput(cycles, 50e3, &Throughput::Bytes(4096)),
+            (50e3 / 4096.0, "cycles/byte"),
+        );
+        close(
+            throughput(cycles, 50e3, &Throughput::Bytes(10_000_000)),
+            (5.12, "cycles/KiB"),
+        );
+        close(
+            throughput(cycles, 50e3, &Throughput::BytesDecimal(10_000_000)),
+            (5.0, "cycles/KB"),
+        );
+        close(
+            throughput(cycles, 50e3, &Throughput::Elements(1)),
+            (50.0, "K cycles/element"),
+        );
+        close(
+            throughput(cycles, 5.0, &Throughput::Elements(10_000_000)),
+            (500.0, "cycles/G elements"),
+        );
+        close(
+            throughput(PerfFormatter::time(), 50e3, &Throughput::Elements(1)),
+            (50.0, "µs/element"),
+        );
+    }
+
+    #[test]
+    fn bytes_per_event() {
+        let cycles = inverted(counts("cycles"));
+        close(
+            throughput(cycles, 1e3, &Throughput::Bytes(4096)),
+            (4.096, "bytes/cycle"),
+        );
+        close(
+            throughput(cycles, 50e3, &Throughput::Bytes(4096)),
+            (81.92, "bytes/K cycles"),
+        );
+        close(
+            throughput(cycles, 50e3, &Throughput::Elements(1)),
+            (20.0, "elements/M cycles"),
+        );
+        close(
+            throughput(cycles, 1024.0, &Throughput::Bytes(10 << 20)),
+            (10.0, "KiB/cycle"),
+        );
+        close(
+            throughput(cycles, 1e3, &Throughput::BytesDecimal(10_000_000)),
+            (10.0, "KB/cycle"),
+        );
+        close(
+            throughput(
+                inverted(counts("branch misses (cpu_core)")),
+                1.0,
+                &Throughput::Elements(2),
+            ),
+            (2.0, "elements/branch miss (cpu_core)"),
+        );
+    }
+
+    #[test]
+    fn bytes_per_second() {
+        let time = inverted(PerfFormatter::time());
+        close(
+            throughput(time, 1e9, &Throughput::Bytes(3)),
+            (3.0, "bytes/s"),
+        );
+        close(
+            throughput(time, 50e3, &Throughput::Bytes(4096)),
+            (78.125, "MiB/s"),
+        );
+        close(
+            throughput(time, 50e3, &Throughput::BytesDecimal(4000)),
+            (80.0, "MB/s"),
+        );
+        close(
+            throughput(time, 50e3, &Throughput::Elements(1)),
+            (20.0, "K elements/s"),
+        );
+    }
+
+    #[test]
+    fn singular_units() {
+        assert_eq!(singular("cycles"), "cycle");
+        assert_eq!(singular("branches"), "branch");
+        assert_eq!(singular("L1D read misses"), "L1D read miss");
+        assert_eq!(singular("context switches"), "context switch");
+        assert_eq!(singular("LLC accesses (cpu_atom)"), "LLC access (cpu_atom)");
+        assert_eq!(singular("cpu/event=0x3c/"), "cpu/event=0x3c/");
+    }
+
+    #[test]
+    fn multiplexed_counts_are_scaled() {
+        let warned = Cell::new(false);
+        let scale = Multiplexing::Scale;
+        assert_eq!(scale.scale(100, 50, 50, &warned), 100);
+        assert_eq!(scale.scale(100, 400, 100, &warned), 400);
+        assert_eq!(scale.scale(u64::MAX / 2, 4, 2, &warned), u64::MAX - 1);
+        assert!(!warned.get());
+    }
+
+    #[test]
+    fn counters_that_never_ran_warn_or_fail() {
+        for policy in [Multiplexing::Scale, Multiplexing::Warn(0.0)] {
+            let warned = Cell::new(false);
+            assert_eq!(policy.scale(0, 400, 0, &warned), 0);
+            assert!(warned.get(), "{policy:?}");
+        }
+        let result = std::panic::catch_unwind(|| {
+            Multiplexing::Fail(0.0).scale(0, 400, 0, &Cell::new(false))
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn multiplexing_warns_below_threshold() {
+        let warned = Cell::new(false);
+        let warn = Multiplexing::Warn(0.5);
+        assert_eq!(warn.scale(100, 400, 200, &warned), 200);
+        assert!(!warned.get());
+        assert_eq!(warn.scale(100, 400, 100, &warned), 400);
+        assert!(warned.get());
+    }
+
+    #[test]
+    fn multiplexing_fails_below_threshold() {
+        let warned = Cell::new(false);
+        let fail = Multiplexing::Fail(0.5);
+        assert_eq!(fail.scale(100, 400, 200, &warned), 200);
+        let result = std::panic::catch_unwind(|| fail.scale(100, 400, 100, &Cell::new(false)));
+        assert!(result.is_err());
+        let result = std::panic::catch_unwind(|| fail.scale(0, 400, 0, &Cell::new(false)));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn multiplexing_thresholds_are_validated() {
+        for threshold in [0.0, 0.5, 1.0] {
+            let _ = PerfMeasurement::default().multiplexing(Multiplexing::Fail(threshold));
+        }
+        for threshold in [-0.1, 1.5, f64::NAN, f64::INFINITY] {
+            for policy in [Multiplexing::Warn(threshold), Multiplexing::Fail(threshold)] {
+                let result =
+                    std::panic::catch_unwind(|| PerfMeasurement::default().multiplexing(policy));
+                assert!(result.is_err(), "{policy:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn perf_names_round_trip() {
+        for mode in PerfMode::ALL {
+            assert_eq!(mode.to_string().parse::<PerfMode>().unwrap(), mode);
+        }
+    }
+
+    #[test]
+    fn perf_name_aliases_ignore_case() {
+        assert_eq!("cycles".parse::<PerfMode>().unwrap(), PerfMode::Cycles);
+        assert_eq!("CPU-Cycles".parse::<PerfMode>().unwrap(), PerfMode::Cycles);
+        assert_eq!("CS".parse::<PerfMode>().unwrap(), PerfMode::ContextSwitches);
+        assert_eq!(
+            "llc-LOAD-misses".parse::<PerfMode>().unwrap(),
+            PerfMode::Cache {
+                which: WhichCache::LL,
+                operation: CacheOp::READ,
+                result: CacheResult::MISS,
+            }
+        );
+        assert_eq!(
+            PerfMode::Cache {
+                which: WhichCache::LL,
+                operation: CacheOp::READ,
+                result: CacheResult::MISS,
+            }
+            .to_string(),
+            "LLC-load-misses"
+        );
+        assert!("cycle".parse::<PerfMode>().is_err());
+    }
+}
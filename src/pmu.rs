@@ -0,0 +1,172 @@
+use std::{fs, path::Path};
+
+use perf_event::{events::Event, Builder};
+
+use crate::{
+    raw::{invalid, read_sysfs, PMU_DEVICES},
+    PerfError,
+};
+
+/// The shift of the PMU type in the config of a generic hardware or
+/// cache event, which selects the core PMU of a hybrid CPU that the
+/// event is counted on.
+const PMU_TYPE_SHIFT: u32 = 32;
+
+/// Which core PMU of a hybrid CPU counts the generic hardware and cache
+/// events of a [`PerfMeasurement`](crate::PerfMeasurement).
+///
+/// Hybrid CPUs, such as Intel CPUs with performance and efficiency
+/// cores, have a separate PMU for each type of core, named `cpu_core`
+/// and `cpu_atom` on Intel. A counter only counts while the thread runs
+/// on a core of its PMU's type. The PMU has no effect on software
+/// events or on raw events, which name their PMU themselves.
+///
+/// Other than with [`Any`](Self::Any), counts are not scaled for
+/// multiplexing and the [`Multiplexing`](crate::Multiplexing) policy
+/// does not apply, as the kernel reports the time spent on cores of the
+/// other type the same way as time lost to multiplexing.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum CorePmu {
+    /// Leave the choice to the kernel. On a hybrid CPU, opening the
+    /// counter may then fail, or the counter may only count on one type
+    /// of core. This is the default.
+    #[default]
+    Any,
+    /// Count on the named PMU, such as `cpu_core` or `cpu_atom`. The
+    /// name is added to the reported unit.
+    Named(String),
+    /// Open a counter on every core PMU listed by
+    /// [`hybrid`](Self::hybrid) and report the sum of their counts.
+    /// This is the same as [`Any`](Self::Any) on CPUs that are not
+    /// hybrid.
+    All,
+}
+
+impl CorePmu {
+    /// List the core PMUs of a hybrid CPU, that is, the PMUs in
+    /// `/sys/bus/event_source/devices` that list the CPUs they count on
+    /// in a `cpus` file, such as `cpu_core` and `cpu_atom` on Intel or
+    /// `armv8_cortex_a53` and `armv8_cortex_a72` on ARM. The list is
+    /// empty on CPUs that are not hybrid.
+    #[must_use]
+    pub fn hybrid() -> Vec<String> {
+        core_pmus(Path::new(PMU_DEVICES))
+    }
+
+    /// The PMU types to open a counter on, with `None` standing for the
+    /// generic type chosen by the kernel.
+    pub(crate) fn types(&self) -> Result<Vec<Option<u32>>, PerfError> {
+        match self {
+            Self::Any => Ok(vec![None]),
+            Self::Named(name) => Ok(vec![Some(pmu_type(name)?)]),
+            Self::All => {
+                let types = Self::hybrid()
+                    .iter()
+                    .map(|name| pmu_type(name).map(Some))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(if types.is_empty() { vec![None] } else { types })
+            }
+        }
+    }
+}
+
+/// The PMUs in `devices` that have a `cpus` file, if there is more than
+/// one.
+fn core_pmus(devices: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(devices)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| devices.join(name).join("cpus").is_file())
+        .collect();
+    if names.len() < 2 {
+        names.clear();
+    }
+    names.sort();
+    names
+}
+
+/// Whether an event is counted by a core PMU, that is, whether it is a
+/// generic hardware or cache event.
+pub(crate) fn is_core_event(event: &Event) -> bool {
+    matches!(event, Event::Hardware(_) | Event::Cache(_))
+}
+
+/// Select the PMU that a generic hardware or cache event is counted on.
+pub(crate) fn select(builder: &mut Builder, event: &Event, pmu_type: u32) {
+    if is_core_event(event) {
+        builder.attrs_mut().config |= u64::from(pmu_type) << PMU_TYPE_SHIFT;
+    }
+}
+
+fn pmu_type(name: &str) -> Result<u32, PerfError> {
+    read_sysfs(&Path::new(PMU_DEVICES).join(name).join("type"))
+        .and_then(|pmu_type| pmu_type.parse().ok())
+        .ok_or_else(|| invalid(format!("unknown PMU `{name}`")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf, process};
+
+    use super::*;
+
+    /// A directory laid out like the PMU devices in sysfs, removed when
+    /// dropped.
+    struct FakeDevices(PathBuf);
+
+    impl FakeDevices {
+        fn new(name: &str, pmus: &[(&str, &str)]) -> Self {
+            let dir =
+                env::temp_dir().join(format!("criterion-linux-perf-{}-{name}", process::id()));
+            for (pmu, file) in pmus {
+                fs::create_dir_all(dir.join(pmu)).unwrap();
+                fs::write(dir.join(pmu).join(file), "0-3\n").unwrap();
+            }
+            Self(dir)
+        }
+    }
+
+    impl Drop for FakeDevices {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn intel_core_pmus() {
+        let devices = FakeDevices::new(
+            "intel",
+            &[
+                ("cpu_core", "cpus"),
+                ("cpu_atom", "cpus"),
+                ("uncore_imc_0", "cpumask"),
+                ("software", "type"),
+            ],
+        );
+        assert_eq!(core_pmus(&devices.0), ["cpu_atom", "cpu_core"]);
+    }
+
+    #[test]
+    fn arm_core_pmus() {
+        let devices = FakeDevices::new(
+            "arm",
+            &[
+                ("armv8_cortex_a72", "cpus"),
+                ("armv8_cortex_a53", "cpus"),
+                ("arm_cmn_0", "cpumask"),
+            ],
+        );
+        assert_eq!(
+            core_pmus(&devices.0),
+            ["armv8_cortex_a53", "armv8_cortex_a72"]
+        );
+    }
+
+    #[test]
+    fn no_core_pmus_unless_hybrid() {
+        let devices = FakeDevices::new("single", &[("armv8_pmuv3_0", "cpus"), ("cpu", "type")]);
+        assert!(core_pmus(&devices.0).is_empty());
+        assert!(core_pmus(&devices.0.join("missing")).is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+use std::io;
+
+use criterion::{
+    black_box,
+    measurement::{Measurement, ValueFormatter},
+    Bencher,
+};
+
+use crate::{
+    group::{GroupCounters, GroupIntermediate},
+    CounterOptions, PerThread, PerfError, PerfFormatter, PerfMode, Scale, Source,
+};
+
+/// A ratio between two perf events, measured by
+/// [`PerfRatioMeasurement`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PerfRatio {
+    /// Instructions retired per CPU cycle.
+    Ipc,
+    /// CPU cycles per instruction retired.
+    Cpi,
+    /// The percentage of branch instructions that were mispredicted.
+    BranchMissRatio,
+    /// The percentage of cache accesses that missed.
+    CacheMissRatio,
+}
+
+impl PerfRatio {
+    /// The numerator and denominator events.
+    fn modes(self) -> (PerfMode, PerfMode) {
+        match self {
+            Self::Ipc => (PerfMode::Instructions, PerfMode::Cycles),
+            Self::Cpi => (PerfMode::Cycles, PerfMode::Instructions),
+            Self::BranchMissRatio => (PerfMode::BranchMisses, PerfMode::Branches),
+            Self::CacheMissRatio => (PerfMode::CacheMisses, PerfMode::CacheRefs),
+        }
+    }
+
+    fn factor(self) -> f64 {
+        match self {
+            Self::Ipc | Self::Cpi => 1.0,
+            Self::BranchMissRatio | Self::CacheMissRatio => 100.0,
+        }
+    }
+
+    fn units(self) -> &'static str {
+        match self {
+            Self::Ipc => "IPC",
+            Self::Cpi => "CPI",
+            Self::BranchMissRatio => "% branch misses",
+            Self::CacheMissRatio => "% cache misses",
+        }
+    }
+}
+
+/// A measurement of the ratio between two events, such as instructions
+/// per cycle or the branch miss rate, counted together in one perf
+/// counter group.
+///
+/// Each value holds the numerator and denominator counts, which are
+/// only divided when converted for Criterion, so that the ratio over
+/// several samples is weighted correctly. Ratios are not divided by the
+/// benchmark throughput.
+///
+/// **Benchmarks must be run with [`bench`](Self::bench), not
+/// `Bencher::iter`.** Criterion treats every value as a total over the
+/// iterations of a sample and divides it by the iteration count, so
+/// with `Bencher::iter` and the other `Bencher` methods the reported
+/// ratio is divided by the iteration count and meaningless.
+/// [`bench`](Self::bench) scales the numerator to make up for this. It
+/// is called on a clone of the measurement given to Criterion:
+///
+/// ```no_run
+/// use criterion::Criterion;
+/// use criterion_linux_perf::{PerfRatio, PerfRatioMeasurement};
+///
+/// let ipc = PerfRatioMeasurement::new(PerfRatio::Ipc);
+/// let mut criterion = Criterion::default().with_measurement(ipc.clone());
+/// criterion.bench_function("sum", |b| {
+///     ipc.bench(b, || (0..1000_u64).sum::<u64>());
+/// });
+/// ```
+#[derive(Clone)]
+pub struct PerfRatioMeasurement {
+    ratio: PerfRatio,
+    members: [Source; 2],
+    formatter: PerfFormatter,
+    group: PerThread<GroupCounters>,
+}
+
+impl PerfRatioMeasurement {
+    /// Create a new measurement of the given [`PerfRatio`].
+    #[must_use]
+    pub fn new(ratio: PerfRatio) -> Self {
+        let (numerator, denominator) = ratio.modes();
+        Self {
+            ratio,
+            members: [
+                Source::Event(numerator.event()),
+                Source::Event(denominator.event()),
+            ],
+            formatter: PerfFormatter::new(ratio.units(), Scale::Ratio),
+            group: PerThread::default(),
+        }
+    }
+
+    /// Create a new measurement of the given [`PerfRatio`], after
+    /// verifying that both counters can actually be opened.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`PerfError`] describing why the counter group could
+    /// not be opened or enabled.
+    pub fn try_new(ratio: PerfRatio) -> Result<Self, PerfError> {
+        let measurement = Self::new(ratio);
+        measurement.with_group(GroupCounters::probe)?;
+        Ok(measurement)
+    }
+
+    /// Benchmark `routine` with `bencher`, such that Criterion reports
+    /// the ratio over each sample. This is the replacement for
+    /// `Bencher::iter` described above.
+    ///
+    /// The counters are those of `self` rather than of the measurement
+    /// given to Criterion, which only serves to format the values.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the counter group cannot be opened or read.
+    pub fn bench<O, R: FnMut() -> O>(&self, bencher: &mut Bencher<'_, Self>, mut routine: R) {
+        bencher.iter_custom(|iters| {
+            let start = self.start();
+            for _ in 0..iters {
+                black_box(routine());
+            }
+            let (numerator, denominator) = self.end(start);
+            // Criterion divides the value by `iters`, which this undoes.
+            (numerator.saturating_mul(iters), denominator)
+        });
+    }
+
+    fn with_group<T>(
+        &self,
+        f: impl FnOnce(&mut GroupCounters) -> io::Result<T>,
+    ) -> Result<T, PerfError> {
+        self.group.with(
+            || GroupCounters::open(&self.members, &CounterOptions::default()),
+            f,
+        )
+    }
+}
+
+impl Measurement for PerfRatioMeasurement {
+    type Intermediate = GroupIntermediate;
+    type Value = (u64, u64);
+
+    fn start(&self) -> Self::Intermediate {
+        GroupIntermediate(
+            self.with_group(GroupCounters::start)
+                .unwrap_or_else(|error| panic!("{error}")),
+        )
+    }
+
+    fn end(&self, intermediate: Self::Intermediate) -> Self::Value {
+        let values = self
+            .with_group(|group| group.stop(&intermediate.0))
+            .unwrap_or_else(|error| panic!("{error}"))
+            .values;
+        (values[0], values[1])
+    }
+
+    fn add(&self, v1: &Self::Value, v2: &Self::Value) -> Self::Value {
+        (v1.0 + v2.0, v1.1 + v2.1)
+    }
+
+    fn zero(&self) -> Self::Value {
+        (0, 0)
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn to_f64(&self, val: &Self::Value) -> f64 {
+        match val {
+            (_, 0) => 0.0,
+            (numerator, denominator) => {
+                *numerator as f64 / *denominator as f64 * self.ratio.factor()
+            }
+        }
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &self.formatter
+    }
+}
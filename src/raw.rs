@@ -0,0 +1,305 @@
+use std::{fs, io, path::Path, str::FromStr};
+
+use perf_event::Builder;
+
+use crate::PerfError;
+
+pub(crate) const PMU_DEVICES: &str = "/sys/bus/event_source/devices";
+
+/// The `perf_event_attr.type` value for raw events on the core CPU PMU.
+const PERF_TYPE_RAW: u32 = 4;
+
+/// A raw PMU event, identified by the PMU type and the event-specific
+/// configuration words passed to `perf_event_open`.
+///
+/// Raw events can be written the same way as for `perf stat -e`, either
+/// as `rNNNN` with a hexadecimal config value for the core CPU PMU, or
+/// as `pmu/term,term,.../`. Each term is either `name=value`, where
+/// `name` is one of `config`, `config1`, `config2` or a field described
+/// in `/sys/bus/event_source/devices/<pmu>/format/`, or the name of an
+/// event listed in `/sys/bus/event_source/devices/<pmu>/events/`:
+///
+/// ```no_run
+/// use criterion_linux_perf::RawEvent;
+///
+/// let l3_misses: RawEvent = "cpu/event=0xd1,umask=0x20/".parse()?;
+/// let cycles: RawEvent = "cpu/cpu-cycles/".parse()?;
+/// let raw: RawEvent = "r20d1".parse()?;
+/// # Ok::<(), criterion_linux_perf::PerfError>(())
+/// ```
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RawEvent {
+    /// The PMU type, as read from
+    /// `/sys/bus/event_source/devices/<pmu>/type`.
+    pub pmu_type: u32,
+    /// The primary event configuration word.
+    pub config: u64,
+    /// The first extended configuration word.
+    pub config1: u64,
+    /// The second extended configuration word.
+    pub config2: u64,
+}
+
+impl RawEvent {
+    /// Create a raw event for the core CPU PMU with the given config
+    /// value.
+    #[must_use]
+    pub const fn new(config: u64) -> Self {
+        Self {
+            pmu_type: PERF_TYPE_RAW,
+            config,
+            config1: 0,
+            config2: 0,
+        }
+    }
+
+    /// Whether the event is on the core CPU PMU.
+    #[cfg(target_arch = "aarch64")]
+    pub(crate) fn is_core_event(&self) -> bool {
+        self.pmu_type == PERF_TYPE_RAW
+    }
+
+    pub(crate) fn configure(&self, builder: &mut Builder) {
+        let attrs = builder.attrs_mut();
+        attrs.type_ = self.pmu_type;
+        attrs.config = self.config;
+        attrs.__bindgen_anon_3.config1 = self.config1;
+        attrs.__bindgen_anon_4.config2 = self.config2;
+    }
+
+    fn parse_pmu(pmu: &str, terms: &str) -> Result<Self, PerfError> {
+        let dir = Path::new(PMU_DEVICES).join(pmu);
+        let pmu_type =
+            read_sysfs(&dir.join("type")).ok_or_else(|| invalid(format!("unknown PMU `{pmu}`")))?;
+        let pmu_type = pmu_type
+            .parse()
+            .map_err(|_| invalid(format!("invalid type for PMU `{pmu}`")))?;
+        let mut event = Self {
+            pmu_type,
+            ..Self::new(0)
+        };
+        event.apply_terms(&dir, terms)?;
+        Ok(event)
+    }
+
+    fn apply_terms(&mut self, dir: &Path, terms: &str) -> Result<(), PerfError> {
+        for term in terms.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            match term.split_once('=') {
+                Some((name, value)) => {
+                    let value = parse_number(value.trim())
+                        .ok_or_else(|| invalid(format!("invalid value in term `{term}`")))?;
+                    self.set_field(dir, name.trim(), value)?;
+                }
+                None => {
+                    if let Some(alias) = read_sysfs(&dir.join("events").join(term)) {
+                        self.apply_terms(dir, &alias)?;
+                    } else {
+                        self.set_field(dir, term, 1)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn set_field(&mut self, dir: &Path, name: &str, value: u64) -> Result<(), PerfError> {
+        let format = match name {
+            "config" | "config1" | "config2" => format!("{name}:0-63"),
+            _ => read_sysfs(&dir.join("format").join(name))
+                .ok_or_else(|| invalid(format!("unknown event or format term `{name}`")))?,
+        };
+        let (word, ranges) = format
+            .split_once(':')
+            .ok_or_else(|| invalid(format!("invalid format for term `{name}`")))?;
+        let word = match word {
+            "config" => &mut self.config,
+            "config1" => &mut self.config1,
+            "config2" => &mut self.config2,
+            _ => return Err(invalid(format!("unsupported format for term `{name}`"))),
+        };
+        let mut value = value;
+        for range in ranges.split(',') {
+            let (low, high) = range.split_once('-').unwrap_or((range, range));
+            let (low, high) = low
+                .parse::<u32>()
+                .ok()
+                .zip(high.parse::<u32>().ok())
+                .filter(|&(low, high)| low <= high && high < 64)
+                .ok_or_else(|| invalid(format!("invalid format for term `{name}`")))?;
+            let width = high - low + 1;
+            let mask = u64::MAX >> (64 - width);
+            *word = (*word & !(mask << low)) | ((value & mask) << low);
+            value = value.checked_shr(width).unwrap_or(0);
+        }
+        if value == 0 {
+            Ok(())
+        } else {
+            Err(invalid(format!("value too large for term `{name}`")))
+        }
+    }
+}
+
+impl FromStr for RawEvent {
+    type Err = PerfError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        if let Some(config) = spec.strip_prefix('r') {
+            if let Ok(config) = u64::from_str_radix(config, 16) {
+                return Ok(Self::new(config));
+            }
+        }
+        spec.strip_suffix('/')
+            .and_then(|spec| spec.split_once('/'))
+            .ok_or_else(|| invalid(format!("`{spec}` is not of the form `pmu/terms/`")))
+            .and_then(|(pmu, terms)| Self::parse_pmu(pmu, terms))
+    }
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+pub(crate) fn read_sysfs(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|contents| contents.trim().to_owned())
+}
+
+pub(crate) fn invalid(message: String) -> PerfError {
+    PerfError::InvalidEvent(io::Error::new(io::ErrorKind::InvalidInput, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, process};
+
+    use super::*;
+
+    /// A directory laid out like a PMU in sysfs, removed when dropped.
+    struct FakePmu(PathBuf);
+
+    impl FakePmu {
+        fn new(name: &str, formats: &[(&str, &str)], events: &[(&str, &str)]) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("criterion-linux-perf-{}-{name}", process::id()));
+            for (subdir, files) in [("format", formats), ("events", events)] {
+                fs::create_dir_all(dir.join(subdir)).unwrap();
+                for (file, contents) in files {
+                    fs::write(dir.join(subdir).join(file), format!("{contents}\n")).unwrap();
+                }
+            }
+            Self(dir)
+        }
+
+        fn apply(&self, terms: &str) -> Result<RawEvent, PerfError> {
+            let mut event = RawEvent::new(0);
+            event.apply_terms(&self.0, terms)?;
+            Ok(event)
+        }
+    }
+
+    impl Drop for FakePmu {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn is_invalid<T>(result: Result<T, PerfError>) -> bool {
+        result.is_err_and(|error| matches!(error, PerfError::InvalidEvent(_)))
+    }
+
+    #[test]
+    fn set_field_packs_bit_ranges() {
+        let pmu = FakePmu::new(
+            "ranges",
+            &[
+                ("event", "config:0-7,32-35"),
+                ("edge", "config:18"),
+                ("ldlat", "config1:0-15"),
+                ("offcore", "config2:0-63"),
+            ],
+            &[],
+        );
+        let mut event = RawEvent::new(0);
+        event.set_field(&pmu.0, "event", 0xabc).unwrap();
+        assert_eq!(event.config, 0xa_0000_00bc);
+        event.set_field(&pmu.0, "edge", 1).unwrap();
+        assert_eq!(event.config, 0xa_0004_00bc);
+        event.set_field(&pmu.0, "event", 0x1).unwrap();
+        assert_eq!(event.config, 0x4_0001);
+        event.set_field(&pmu.0, "ldlat", 3).unwrap();
+        assert_eq!(event.config1, 3);
+        event.set_field(&pmu.0, "offcore", u64::MAX).unwrap();
+        assert_eq!(event.config2, u64::MAX);
+        event.set_field(&pmu.0, "config1", u64::MAX).unwrap();
+        assert_eq!(event.config1, u64::MAX);
+    }
+
+    #[test]
+    fn set_field_rejects_bad_values_and_formats() {
+        let pmu = FakePmu::new(
+            "errors",
+            &[
+                ("event", "config:0-7,32-35"),
+                ("edge", "config:18"),
+                ("backwards", "config:7-0"),
+                ("wide", "config:0-64"),
+                ("word", "config3:0-7"),
+                ("colon", "config"),
+            ],
+            &[],
+        );
+        let mut event = RawEvent::new(0);
+        assert!(event.set_field(&pmu.0, "event", 0xfff).is_ok());
+        assert!(is_invalid(event.set_field(&pmu.0, "event", 0x1000)));
+        assert!(is_invalid(event.set_field(&pmu.0, "edge", 2)));
+        for name in ["backwards", "wide", "word", "colon", "missing"] {
+            assert!(is_invalid(event.set_field(&pmu.0, name, 0)), "{name}");
+        }
+    }
+
+    #[test]
+    fn apply_terms_resolves_values_flags_and_aliases() {
+        let pmu = FakePmu::new(
+            "terms",
+            &[
+                ("event", "config:0-7"),
+                ("umask", "config:8-15"),
+                ("edge", "config:18"),
+            ],
+            &[("l3-misses", "event=0xd1,umask=0x20")],
+        );
+        let event = pmu.apply("event=0x3c, umask=0X1,edge").unwrap();
+        assert_eq!(event.config, 0x4_013c);
+        let event = pmu.apply("l3-misses,config1=12").unwrap();
+        assert_eq!((event.config, event.config1), (0x20d1, 12));
+        assert!(is_invalid(pmu.apply("event=0xzz")));
+        assert!(is_invalid(pmu.apply("event=-1")));
+        assert!(is_invalid(pmu.apply("nosuchterm")));
+    }
+
+    #[test]
+    fn parse_raw_config() {
+        let event: RawEvent = "r20d1".parse().unwrap();
+        assert_eq!(event, RawEvent::new(0x20d1));
+        assert_eq!(event.pmu_type, PERF_TYPE_RAW);
+    }
+
+    #[test]
+    fn parse_malformed_specs() {
+        for spec in [
+            "",
+            "r",
+            "rxyz",
+            "cpu",
+            "cpu/event=1",
+            "/event=1/",
+            "criterion-linux-perf-no-such-pmu/event=1/",
+        ] {
+            assert!(is_invalid(spec.parse::<RawEvent>()), "{spec}");
+        }
+    }
+}
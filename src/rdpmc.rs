@@ -0,0 +1,206 @@
+use std::{
+    io,
+    os::unix::io::AsRawFd,
+    ptr::{self, addr_of},
+    sync::atomic::{compiler_fence, Ordering},
+};
+
+use perf_event::{Builder, CountAndTime, Counter};
+
+use crate::Source;
+
+/// The start of the kernel's `struct perf_event_mmap_page`, up to the
+/// fields needed to read a counter from user space.
+#[repr(C)]
+struct MmapPage {
+    _version: u32,
+    _compat_version: u32,
+    lock: u32,
+    index: u32,
+    offset: i64,
+    time_enabled: u64,
+    time_running: u64,
+    capabilities: u64,
+    pmc_width: u16,
+}
+
+/// The `cap_user_rdpmc` bit of `perf_event_mmap_page.capabilities`.
+const CAP_USER_RDPMC: u64 = 1 << 2;
+
+/// The `rdpmc` bit of the arm64 PMU's `config1`, which requests user
+/// space access to the counter.
+#[cfg(target_arch = "aarch64")]
+const ARM_CONFIG1_RDPMC: u64 = 1 << 1;
+
+/// The metadata page of a counter, mapped into memory so that the
+/// counter can be read with `rdpmc` (x86-64) or `mrs` (aarch64) instead
+/// of a `read` system call.
+pub(crate) struct UserPage {
+    page: *const MmapPage,
+    len: usize,
+}
+
+// SAFETY: the mapping belongs to the process rather than the thread
+// that created it, and is only read, so it may be moved to and
+// unmapped by another thread. Reading the counter it describes from
+// another thread is harmless, as `read` only returns values when the
+// counter is scheduled on the CPU it runs on.
+unsafe impl Send for UserPage {}
+
+impl UserPage {
+    /// Map the metadata page of `counter`.
+    pub(crate) fn map(counter: &Counter) -> io::Result<Self> {
+        // SAFETY: mapping a perf counter's file descriptor has no effect
+        // on memory outside the new mapping, and the page size reported
+        // by `sysconf` is the size the kernel requires.
+        unsafe {
+            let len = usize::try_from(libc::sysconf(libc::_SC_PAGESIZE))
+                .map_err(|_| io::Error::last_os_error())?;
+            let page = libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                counter.as_raw_fd(),
+                0,
+            );
+            if page == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self {
+                page: page.cast(),
+                len,
+            })
+        }
+    }
+
+    /// Read the counter from user space, following the seqlock protocol
+    /// of the metadata page. This returns `None` when the kernel does
+    /// not permit it (`cap_user_rdpmc` is not set) or the counter is not
+    /// currently scheduled on the PMU, in which case the counter must be
+    /// read with a system call instead.
+    ///
+    /// The enabled and running times are those of the last time the
+    /// kernel updated the page, which is enough to tell whether the
+    /// counter was multiplexed between two reads.
+    pub(crate) fn read(&self) -> Option<CountAndTime> {
+        let page = self.page;
+        loop {
+            // SAFETY: the page stays mapped for the lifetime of `self`,
+            // and the kernel only ever updates it under the seqlock.
+            unsafe {
+                let seq = addr_of!((*page).lock).read_volatile();
+                compiler_fence(Ordering::SeqCst);
+                let capabilities = addr_of!((*page).capabilities).read_volatile();
+                let index = addr_of!((*page).index).read_volatile();
+                if capabilities & CAP_USER_RDPMC == 0 || index == 0 {
+                    return None;
+                }
+                let offset = addr_of!((*page).offset).read_volatile();
+                let width = u32::from(addr_of!((*page).pmc_width).read_volatile());
+                let time_enabled = addr_of!((*page).time_enabled).read_volatile();
+                let time_running = addr_of!((*page).time_running).read_volatile();
+                let pmc = read_pmc(index - 1)?;
+                compiler_fence(Ordering::SeqCst);
+                if addr_of!((*page).lock).read_volatile() != seq {
+                    continue;
+                }
+                if width == 0 || width > 64 {
+                    return None;
+                }
+                let shift = 64 - width;
+                #[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+                let pmc = (((pmc << shift) as i64) >> shift) as u64;
+                #[allow(clippy::cast_sign_loss)]
+                let count = (offset as u64).wrapping_add(pmc);
+                return Some(CountAndTime {
+                    count,
+                    time_enabled,
+                    time_running,
+                });
+            }
+        }
+    }
+}
+
+impl Drop for UserPage {
+    fn drop(&mut self) {
+        // SAFETY: the page was mapped with this length in `map`.
+        unsafe {
+            libc::munmap(self.page.cast_mut().cast(), self.len);
+        }
+    }
+}
+
+/// Ask the kernel to permit reading a counter from user space, where
+/// that has to be requested when the counter is opened.
+pub(crate) fn request(builder: &mut Builder, source: &Source) {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let core = match source {
+            Source::Event(event) => crate::pmu::is_core_event(event),
+            Source::Raw(raw) => raw.is_core_event(),
+            Source::WallTime => false,
+        };
+        if core {
+            builder.attrs_mut().__bindgen_anon_3.config1 |= ARM_CONFIG1_RDPMC;
+        }
+    }
+    #[cfg(not(target_arch = "aarch64"))]
+    let _ = (builder, source);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[allow(clippy::unnecessary_wraps)]
+fn read_pmc(index: u32) -> Option<u64> {
+    let (low, high): (u32, u32);
+    // SAFETY: the kernel has set `cap_user_rdpmc`, so `rdpmc` is
+    // permitted for the counter at this index.
+    unsafe {
+        std::arch::asm!(
+            "rdpmc",
+            in("ecx") index,
+            out("eax") low,
+            out("edx") high,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    Some(u64::from(high) << 32 | u64::from(low))
+}
+
+#[cfg(target_arch = "aarch64")]
+fn read_pmc(index: u32) -> Option<u64> {
+    macro_rules! mrs {
+        ($template:expr) => {{
+            let value: u64;
+            // SAFETY: the kernel has set `cap_user_rdpmc`, so user space
+            // access to the PMU registers is enabled.
+            unsafe {
+                std::arch::asm!(
+                    $template,
+                    out(reg) value,
+                    options(nomem, nostack, preserves_flags),
+                );
+            }
+            value
+        }};
+    }
+    macro_rules! pmevcntr {
+        ( $( $n:literal )* ) => {
+            match index {
+                $( $n => mrs!(concat!("mrs {}, pmevcntr", $n, "_el0")), )*
+                31 => mrs!("mrs {}, pmccntr_el0"),
+                _ => return None,
+            }
+        };
+    }
+    Some(pmevcntr!(
+        0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15
+        16 17 18 19 20 21 22 23 24 25 26 27 28 29 30
+    ))
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn read_pmc(_index: u32) -> Option<u64> {
+    None
+}
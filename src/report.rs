@@ -0,0 +1,243 @@
+use std::{
+    env,
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{GroupSamples, PerfError};
+
+/// The environment variable that Criterion reads its output directory
+/// from.
+const CRITERION_HOME_VAR: &str = "CRITERION_HOME";
+
+/// The environment variable that Cargo reads its target directory from.
+const TARGET_DIR_VAR: &str = "CARGO_TARGET_DIR";
+
+/// The characters that Criterion replaces with `_` in directory names.
+const UNSAFE_CHARS: [char; 10] = ['?', '"', '/', '\\', '*', '<', '>', ':', '|', '^'];
+
+/// The length in bytes that Criterion truncates directory names to.
+const MAX_DIRECTORY_NAME_LEN: usize = 64;
+
+impl GroupSamples {
+    /// Remove the values recorded so far, as for [`take`](Self::take),
+    /// and write them to `perf/samples.json` in Criterion's output
+    /// directory for the benchmark `id`, such as
+    /// `target/criterion/<id>/perf/samples.json`. Return the path of the
+    /// file.
+    ///
+    /// `id` is the benchmark ID as Criterion reports it, such as
+    /// `group/function` or `group/function/parameter`. Each part is
+    /// made safe for use as a directory name in the same way as
+    /// Criterion does, so that `group/String::new` is written under
+    /// `group/String__new`. The output directory is `$CRITERION_HOME`,
+    /// or `criterion` in `$CARGO_TARGET_DIR` or `target`, as for
+    /// Criterion itself.
+    ///
+    /// For each member of the group, the file holds its label, its
+    /// value for every sample, and the mean, median and median absolute
+    /// deviation of those values, or `null` if there are no samples:
+    ///
+    /// ```json
+    /// {
+    ///   "id": "group/function",
+    ///   "events": [
+    ///     {
+    ///       "label": "instructions",
+    ///       "samples": [1204, 1198, 1203],
+    ///       "mean": 1201.6666666666667,
+    ///       "median": 1203,
+    ///       "mad": 1
+    ///     }
+    ///   ]
+    /// }
+    /// ```
+    ///
+    /// The values are the counts of whole samples, as measured, rather
+    /// than per iteration. They are only comparable between samples
+    /// when every sample runs the same number of iterations, as with
+    /// Criterion's `SamplingMode::Flat`. The samples taken while
+    /// Criterion is warming up are included, so call this once before
+    /// the benchmark to discard earlier samples if need be.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`PerfError::Io`] if the file could not be written, or
+    /// if a sample does not hold one value per label.
+    pub fn write_report(&self, id: &str) -> Result<PathBuf, PerfError> {
+        let labels = self.labels();
+        let samples = self.take();
+        let json = report(id, &labels, &samples)?;
+        let dir = output_dir().join(directory_name(id)).join("perf");
+        let path = dir.join("samples.json");
+        fs::create_dir_all(&dir)
+            .and_then(|()| fs::write(&path, json))
+            .map_err(|error| {
+                PerfError::Io(io::Error::new(
+                    error.kind(),
+                    format!("cannot write {}: {error}", path.display()),
+                ))
+            })?;
+        Ok(path)
+    }
+}
+
+fn output_dir() -> PathBuf {
+    if let Some(home) = env::var_os(CRITERION_HOME_VAR) {
+        return home.into();
+    }
+    env::var_os(TARGET_DIR_VAR)
+        .map_or_else(|| Path::new("target").to_owned(), PathBuf::from)
+        .join("criterion")
+}
+
+/// The directory Criterion names after the benchmark `id`, with each
+/// `/`-separated part made safe as by Criterion's `make_filename_safe`.
+fn directory_name(id: &str) -> PathBuf {
+    id.split('/')
+        .map(|part| {
+            let mut part = part.replace(UNSAFE_CHARS, "_");
+            let mut len = part.len().min(MAX_DIRECTORY_NAME_LEN);
+            while !part.is_char_boundary(len) {
+                len -= 1;
+            }
+            part.truncate(len);
+            part
+        })
+        .collect()
+}
+
+fn report(id: &str, labels: &[&str], samples: &[Vec<u64>]) -> Result<String, PerfError> {
+    if let Some(sample) = samples.iter().find(|sample| sample.len() != labels.len()) {
+        return Err(PerfError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "a sample holds {} values for {} labels",
+                sample.len(),
+                labels.len()
+            ),
+        )));
+    }
+    let mut json = format!("{{\n  \"id\": {},\n  \"events\": [", string(id));
+    for (member, label) in labels.iter().enumerate() {
+        let counts: Vec<String> = samples
+            .iter()
+            .map(|sample| sample[member].to_string())
+            .collect();
+        #[allow(clippy::cast_precision_loss)]
+        let values: Vec<f64> = samples.iter().map(|sample| sample[member] as f64).collect();
+        let _ = write!(
+            json,
+            "{}\n    {{\n      \"label\": {},\n      \"samples\": [{}],\n      \
+             \"mean\": {},\n      \"median\": {},\n      \"mad\": {}\n    }}",
+            if member == 0 { "" } else { "," },
+            string(label),
+            counts.join(", "),
+            number(mean(&values)),
+            number(median(values.clone())),
+            number(mad(&values)),
+        );
+    }
+    json.push_str("\n  ]\n}\n");
+    Ok(json)
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn mean(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    Some(values.iter().sum::<f64>() / values.len() as f64)
+}
+
+fn median(mut values: Vec<f64>) -> Option<f64> {
+    values.sort_by(f64::total_cmp);
+    let middle = values.len() / 2;
+    match values.len() {
+        0 => None,
+        len if len % 2 == 1 => Some(values[middle]),
+        _ => Some(f64::midpoint(values[middle - 1], values[middle])),
+    }
+}
+
+/// The median absolute deviation from the median.
+fn mad(values: &[f64]) -> Option<f64> {
+    let center = median(values.to_vec())?;
+    median(values.iter().map(|value| (value - center).abs()).collect())
+}
+
+fn number(value: Option<f64>) -> String {
+    value.map_or_else(|| "null".to_owned(), |value| value.to_string())
+}
+
+/// Quote and escape a string as JSON.
+fn string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", u32::from(c));
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn median_of_odd_and_even_lengths() {
+        assert_eq!(median(vec![]), None);
+        assert_eq!(median(vec![3.0, 1.0, 2.0]), Some(2.0));
+        assert_eq!(median(vec![4.0, 1.0, 3.0, 2.0]), Some(2.5));
+    }
+
+    #[test]
+    fn mad_of_odd_and_even_lengths() {
+        assert_eq!(mad(&[]), None);
+        assert_eq!(mad(&[1.0, 2.0, 3.0, 4.0, 100.0]), Some(1.0));
+        assert_eq!(mad(&[1.0, 2.0, 4.0, 8.0]), Some(1.5));
+    }
+
+    #[test]
+    fn string_escaping() {
+        assert_eq!(string("cycles"), r#""cycles""#);
+        assert_eq!(string(r#"a "b" \c"#), r#""a \"b\" \\c""#);
+        assert_eq!(string("a\nb\tc\u{1}"), r#""a\nb\u0009c\u0001""#);
+        assert_eq!(string("µs"), "\"µs\"");
+    }
+
+    #[test]
+    fn directory_names_match_criterion() {
+        assert_eq!(
+            directory_name("grp/String::new/a*b"),
+            Path::new("grp/String__new/a_b")
+        );
+        let long = "é".repeat(40);
+        let name = directory_name(&long);
+        assert_eq!(name.to_str().map(str::len), Some(64));
+    }
+
+    #[test]
+    fn report_with_samples() {
+        let json = report("id", &["ns", "faults"], &[vec![10, 1], vec![30, 3]]).unwrap();
+        assert!(json.contains(r#""samples": [10, 30],"#));
+        assert!(json.contains(r#""mean": 20,"#));
+        assert!(json.contains(r#""mad": 1"#));
+    }
+
+    #[test]
+    fn report_rejects_mismatched_samples() {
+        assert!(report("id", &["ns", "faults"], &[vec![10, 1], vec![30]]).is_err());
+        assert!(report("id", &["ns"], &[vec![10, 1]]).is_err());
+    }
+}
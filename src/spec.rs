@@ -0,0 +1,198 @@
+use std::env;
+
+use crate::{raw::invalid, PerfError, PerfMeasurement, PerfMode, RawEvent};
+
+/// The environment variable read by [`PerfMeasurement::from_env`].
+const EVENT_VAR: &str = "CRITERION_PERF_EVENT";
+
+impl PerfMeasurement {
+    /// Create a new measurement of the event named in the
+    /// `CRITERION_PERF_EVENT` environment variable, or of the default
+    /// [`PerfMode`]`::Instructions` if it is not set. This lets the
+    /// same compiled benchmark measure different events, for example
+    /// `CRITERION_PERF_EVENT=cycles cargo bench`.
+    ///
+    /// The event is written as for [`from_event_spec`](Self::from_event_spec).
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`PerfError`] if the variable is set but does not
+    /// contain a valid event.
+    pub fn from_env() -> Result<Self, PerfError> {
+        match env::var(EVENT_VAR) {
+            Ok(spec) => Self::from_event_spec(spec.trim()),
+            Err(env::VarError::NotPresent) => Ok(Self::default()),
+            Err(env::VarError::NotUnicode(_)) => {
+                Err(invalid(format!("{EVENT_VAR} is not valid Unicode")))
+            }
+        }
+    }
+
+    /// Create a new measurement of an event written as for
+    /// `perf stat -e`. This is one of:
+    ///
+    /// - the name of a generic hardware or software event, or one of its
+    ///   aliases, such as `instructions`, `cycles` or `page-faults`;
+    /// - the name of a hardware cache event, such as
+    ///   `L1-dcache-load-misses` or `LLC-stores`;
+    /// - a raw event, as accepted by [`RawEvent`], which is reported
+    ///   with the event itself as the unit.
+    ///
+    /// The event may be followed by the modifiers `u`, `k` and `h`,
+    /// after a `:` or the final `/` of a raw event, to count only in
+    /// user space, the kernel and the hypervisor respectively, as in
+    /// `cycles:u` or `cpu/event=0x3c/k`. Context switches and CPU
+    /// migrations are counted in the kernel regardless, as described
+    /// for [`Privilege`](crate::Privilege).
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`PerfError::InvalidEvent`] if the event is unknown or
+    /// cannot be parsed.
+    pub fn from_event_spec(spec: &str) -> Result<Self, PerfError> {
+        // Modifiers follow the final `/` of a complete `pmu/terms/` raw
+        // event, or a `:` otherwise.
+        let (event, modifiers) = match spec.rfind('/') {
+            Some(slash) if spec[..slash].contains('/') => {
+                let (event, modifiers) = spec.split_at(slash + 1);
+                (event, modifiers.strip_prefix(':').unwrap_or(modifiers))
+            }
+            _ => spec.split_once(':').unwrap_or((spec, "")),
+        };
+        let measurement = match PerfMode::from_perf_name(event) {
+            Some(mode) => Self::new(mode),
+            None if event.contains('/') || is_raw_config(event) => {
+                Self::raw_event(event.parse::<RawEvent>()?, event)
+            }
+            None => return Err(invalid(format!("unknown perf event `{event}`"))),
+        };
+        if modifiers.is_empty() {
+            return Ok(measurement);
+        }
+        if let Some(modifier) = modifiers.chars().find(|c| !"ukh".contains(*c)) {
+            return Err(invalid(format!(
+                "unsupported modifier `{modifier}` in perf event `{spec}`"
+            )));
+        }
+        Ok(measurement.configure(|options| {
+            options.exclude_user = !modifiers.contains('u');
+            options.exclude_kernel = !modifiers.contains('k');
+            options.exclude_hv = !modifiers.contains('h');
+        }))
+    }
+}
+
+/// Whether an event is written as `rNNNN`, with a hexadecimal config.
+fn is_raw_config(event: &str) -> bool {
+    event
+        .strip_prefix('r')
+        .is_some_and(|config| u64::from_str_radix(config, 16).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use perf_event::events::{Event, Hardware, Software};
+
+    use super::*;
+    use crate::Source;
+
+    fn parse(spec: &str) -> PerfMeasurement {
+        PerfMeasurement::from_event_spec(spec).unwrap()
+    }
+
+    fn event(measurement: &PerfMeasurement) -> Option<Event> {
+        match &measurement.source {
+            Source::Event(event) => Some(event.clone()),
+            _ => None,
+        }
+    }
+
+    fn raw(measurement: &PerfMeasurement) -> Option<RawEvent> {
+        match &measurement.source {
+            Source::Raw(raw) => Some(*raw),
+            _ => None,
+        }
+    }
+
+    /// The `exclude_user`, `exclude_kernel` and `exclude_hv` options.
+    fn excludes(measurement: &PerfMeasurement) -> (bool, bool, bool) {
+        let options = &measurement.options;
+        (
+            options.exclude_user,
+            options.exclude_kernel,
+            options.exclude_hv,
+        )
+    }
+
+    fn error(spec: &str) -> String {
+        match PerfMeasurement::from_event_spec(spec) {
+            Ok(_) => panic!("`{spec}` was accepted"),
+            Err(PerfError::InvalidEvent(error)) => error.to_string(),
+            Err(error) => panic!("`{spec}` gave {error:?}"),
+        }
+    }
+
+    #[test]
+    fn generic_events_and_modifiers() {
+        let default = excludes(&PerfMeasurement::default());
+        let cycles = parse("cycles");
+        assert_eq!(event(&cycles), Some(Hardware::CPU_CYCLES.into()));
+        assert_eq!(excludes(&cycles), default);
+        let cycles = parse("cycles:u");
+        assert_eq!(event(&cycles), Some(Hardware::CPU_CYCLES.into()));
+        assert_eq!(excludes(&cycles), (false, true, true));
+        assert_eq!(excludes(&parse("cycles:kh")), (true, false, false));
+        assert_eq!(excludes(&parse("cycles:ukh")), (false, false, false));
+        let cs = parse("cs");
+        assert_eq!(event(&cs), Some(Software::CONTEXT_SWITCHES.into()));
+        assert_eq!(cs.formatter.units, "context switches");
+    }
+
+    #[test]
+    fn cache_events() {
+        let misses = parse("L1-dcache-load-misses:u");
+        assert_eq!(
+            event(&misses),
+            Some(
+                PerfMode::Cache {
+                    which: crate::WhichCache::L1D,
+                    operation: crate::CacheOp::READ,
+                    result: crate::CacheResult::MISS,
+                }
+                .event()
+            )
+        );
+        assert_eq!(excludes(&misses), (false, true, true));
+    }
+
+    #[test]
+    fn raw_events() {
+        let config = parse("r20d1");
+        assert_eq!(raw(&config), Some(RawEvent::new(0x20d1)));
+        assert_eq!(config.formatter.units, "r20d1");
+        assert_eq!(excludes(&parse("r20d1:k")), (true, false, true));
+        let pmu = PerfMeasurement::from_event_spec("cpu/event=0x3c/k");
+        if let Ok(pmu) = pmu {
+            assert_eq!(raw(&pmu).map(|raw| raw.config & 0xff), Some(0x3c));
+            assert_eq!(pmu.formatter.units, "cpu/event=0x3c/");
+            assert_eq!(excludes(&pmu), (true, false, true));
+        } else {
+            // Without a `cpu` PMU, only the event itself is rejected.
+            let message = error("cpu/event=0x3c/k");
+            assert!(message.contains("PMU `cpu`"), "{message}");
+        }
+    }
+
+    #[test]
+    fn invalid_specs() {
+        assert!(error("no-such-event").contains("unknown perf event"));
+        assert!(error("cycles:x").contains("unsupported modifier `x`"));
+        assert!(error("cycles:uz").contains("unsupported modifier `z`"));
+        assert!(error("r20d1:q").contains("unsupported modifier `q`"));
+        let message = error("cpu/event=1");
+        assert!(
+            message.contains("`cpu/event=1` is not of the form"),
+            "{message}"
+        );
+    }
+}